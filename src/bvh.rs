@@ -0,0 +1,519 @@
+use crate::{geo::Vec3, render::Ray};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn empty() -> Self {
+        Self {
+            min: Vec3::of(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Vec3::of(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn of(a: Vec3, b: Vec3) -> Self {
+        Self {
+            min: Vec3::of(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z)),
+            max: Vec3::of(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z)),
+        }
+    }
+
+    pub fn grow(&mut self, p: Vec3) {
+        self.min = Vec3::of(
+            self.min.x.min(p.x),
+            self.min.y.min(p.y),
+            self.min.z.min(p.z),
+        );
+        self.max = Vec3::of(
+            self.max.x.max(p.x),
+            self.max.y.max(p.y),
+            self.max.z.max(p.z),
+        );
+    }
+
+    pub fn union(&self, rhs: &Aabb) -> Self {
+        Self {
+            min: Vec3::of(
+                self.min.x.min(rhs.min.x),
+                self.min.y.min(rhs.min.y),
+                self.min.z.min(rhs.min.z),
+            ),
+            max: Vec3::of(
+                self.max.x.max(rhs.max.x),
+                self.max.y.max(rhs.max.y),
+                self.max.z.max(rhs.max.z),
+            ),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Slab test. Returns the entry distance if the ray overlaps the box within `[t_min, t_max]`.
+    #[inline(always)]
+    fn hit(&self, base: &Vec3, inv_dir: &Vec3, t_min: f32, t_max: f32) -> Option<f32> {
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+        for (min, max, base, inv) in [
+            (self.min.x, self.max.x, base.x, inv_dir.x),
+            (self.min.y, self.max.y, base.y, inv_dir.y),
+            (self.min.z, self.max.z, base.z, inv_dir.z),
+        ] {
+            let a = (min - base) * inv;
+            let b = (max - base) * inv;
+            // f32::min/max ignore the NaN produced by 0 * inf when the ray lies in a slab plane.
+            t0 = t0.max(a.min(b));
+            t1 = t1.min(a.max(b));
+        }
+        if t0 <= t1 { Some(t0) } else { None }
+    }
+}
+
+/// Leaves store `count > 0` primitives starting at `first` in `Bvh::indices`.
+/// Interior nodes have `count == 0`, their left child directly follows them and
+/// the right child lives at `first`.
+#[derive(Clone, Debug)]
+struct BvhNode {
+    bounds: Aabb,
+    first: u32,
+    count: u32,
+    axis: u8,
+}
+
+/// Bounding volume hierarchy over a list of primitives, built with a binned
+/// surface area heuristic. The hierarchy only knows the primitive bounds,
+/// intersecting the primitives themselves is left to the caller.
+#[derive(Clone, Debug)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<u32>,
+}
+
+const SAH_BINS: usize = 16;
+const TRAVERSAL_COST: f32 = 0.125;
+const MAX_LEAF_SIZE: usize = 8;
+/// Keeps the traversal stack in `closest_hit` bounded.
+const MAX_DEPTH: usize = 48;
+
+struct BuildPrimitive {
+    bounds: Aabb,
+    centroid: Vec3,
+}
+
+impl Bvh {
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let prims: Vec<BuildPrimitive> = bounds
+            .iter()
+            .map(|b| BuildPrimitive {
+                bounds: *b,
+                centroid: b.centroid(),
+            })
+            .collect();
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(2 * prims.len().max(1)),
+            indices: (0..prims.len() as u32).collect(),
+        };
+        if !prims.is_empty() {
+            bvh.build_node(&prims, 0, prims.len(), 0);
+        }
+        bvh
+    }
+
+    fn build_node(
+        &mut self,
+        prims: &[BuildPrimitive],
+        start: usize,
+        end: usize,
+        depth: usize,
+    ) -> usize {
+        let node_index = self.nodes.len();
+        let mut bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for i in &self.indices[start..end] {
+            bounds = bounds.union(&prims[*i as usize].bounds);
+            centroid_bounds.grow(prims[*i as usize].centroid);
+        }
+        self.nodes.push(BvhNode {
+            bounds,
+            first: start as u32,
+            count: (end - start) as u32,
+            axis: 0,
+        });
+
+        let count = end - start;
+        if count <= 2 || depth >= MAX_DEPTH {
+            return node_index;
+        }
+        let Some((split_axis, mid)) = self.find_split(prims, start, end, &bounds, &centroid_bounds)
+        else {
+            return node_index;
+        };
+
+        self.build_node(prims, start, mid, depth + 1);
+        let right = self.build_node(prims, mid, end, depth + 1);
+        let node = &mut self.nodes[node_index];
+        node.first = right as u32;
+        node.count = 0;
+        node.axis = split_axis as u8;
+        node_index
+    }
+
+    /// Picks the cheapest binned SAH split over all three axes and partitions
+    /// `indices[start..end]` accordingly. Returns `None` if a leaf is cheaper.
+    fn find_split(
+        &mut self,
+        prims: &[BuildPrimitive],
+        start: usize,
+        end: usize,
+        bounds: &Aabb,
+        centroid_bounds: &Aabb,
+    ) -> Option<(usize, usize)> {
+        let count = end - start;
+        let mut best: Option<(f32, usize, usize)> = None;
+        for a in 0..3 {
+            let lo = centroid_bounds.min[a];
+            let extent = centroid_bounds.max[a] - lo;
+            if extent <= 0.0 {
+                continue;
+            }
+            let bin_of =
+                |c: &Vec3| (((c[a] - lo) / extent * SAH_BINS as f32) as usize).min(SAH_BINS - 1);
+            let mut bin_bounds = [Aabb::empty(); SAH_BINS];
+            let mut bin_counts = [0usize; SAH_BINS];
+            for i in &self.indices[start..end] {
+                let prim = &prims[*i as usize];
+                let b = bin_of(&prim.centroid);
+                bin_bounds[b] = bin_bounds[b].union(&prim.bounds);
+                bin_counts[b] += 1;
+            }
+            let mut left_area = [0.0f32; SAH_BINS];
+            let mut left_count = [0usize; SAH_BINS];
+            let mut acc = Aabb::empty();
+            let mut n = 0;
+            for b in 0..SAH_BINS - 1 {
+                acc = acc.union(&bin_bounds[b]);
+                n += bin_counts[b];
+                left_area[b] = acc.surface_area();
+                left_count[b] = n;
+            }
+            let mut acc = Aabb::empty();
+            let mut n = 0;
+            for b in (1..SAH_BINS).rev() {
+                acc = acc.union(&bin_bounds[b]);
+                n += bin_counts[b];
+                let cost =
+                    left_area[b - 1] * left_count[b - 1] as f32 + acc.surface_area() * n as f32;
+                if left_count[b - 1] > 0 && n > 0 && best.is_none_or(|(c, _, _)| cost < c) {
+                    best = Some((cost, a, b));
+                }
+            }
+        }
+
+        let (cost, split_axis, split_bin) = best?;
+        let parent_area = bounds.surface_area();
+        let split_cost = if parent_area > 0.0 {
+            TRAVERSAL_COST + cost / parent_area
+        } else {
+            TRAVERSAL_COST
+        };
+        if split_cost >= count as f32 && count <= MAX_LEAF_SIZE {
+            return None;
+        }
+
+        let lo = centroid_bounds.min[split_axis];
+        let extent = centroid_bounds.max[split_axis] - lo;
+        let goes_left = |i: &u32| {
+            let c = prims[*i as usize].centroid[split_axis];
+            (((c - lo) / extent * SAH_BINS as f32) as usize).min(SAH_BINS - 1) < split_bin
+        };
+        let slice = &mut self.indices[start..end];
+        let mut mid = 0;
+        for i in 0..slice.len() {
+            if goes_left(&slice[i]) {
+                slice.swap(i, mid);
+                mid += 1;
+            }
+        }
+        Some((split_axis, start + mid))
+    }
+
+    /// Finds the closest primitive hit along `ray` within `[t_min, t_max]`.
+    ///
+    /// `hit` is called with a primitive index and the current upper bound for `t`
+    /// and returns the hit distance together with the hit payload. Ties are
+    /// resolved in favour of the lower primitive index, so the result matches a
+    /// linear scan over all primitives.
+    pub fn closest_hit<T>(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        mut hit: impl FnMut(usize, f32) -> Option<(f32, T)>,
+    ) -> Option<T> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let inv_dir = Vec3::of(1.0 / ray.dir.x, 1.0 / ray.dir.y, 1.0 / ray.dir.z);
+        let dir_negative = [ray.dir.x < 0.0, ray.dir.y < 0.0, ray.dir.z < 0.0];
+
+        let mut closest: Option<(f32, usize, T)> = None;
+        let mut t_closest = t_max;
+        let mut stack = [0usize; 64];
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let node_index = stack[stack_len];
+            let node = &self.nodes[node_index];
+            if node
+                .bounds
+                .hit(&ray.base, &inv_dir, t_min, t_closest)
+                .is_none()
+            {
+                continue;
+            }
+            if node.count > 0 {
+                let first = node.first as usize;
+                for &prim in &self.indices[first..first + node.count as usize] {
+                    let prim = prim as usize;
+                    let Some((t, payload)) = hit(prim, t_closest) else {
+                        continue;
+                    };
+                    let better = match &closest {
+                        None => true,
+                        Some((best_t, best_prim, _)) => {
+                            t < *best_t || (t == *best_t && prim < *best_prim)
+                        }
+                    };
+                    if better {
+                        t_closest = t;
+                        closest = Some((t, prim, payload));
+                    }
+                }
+            } else {
+                let left = node_index + 1;
+                let right = node.first as usize;
+                // Push the far child first so the near child is visited next.
+                let (near, far) = if dir_negative[node.axis as usize] {
+                    (right, left)
+                } else {
+                    (left, right)
+                };
+                stack[stack_len] = far;
+                stack[stack_len + 1] = near;
+                stack_len += 2;
+            }
+        }
+        closest.map(|(_, _, payload)| payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mesh::intersect_triangle,
+        random::{MyRng, MySmallRng},
+    };
+
+    enum Primitive {
+        Sphere { center: Vec3, radius: f32 },
+        Triangle([Vec3; 3]),
+    }
+
+    impl Primitive {
+        fn bounds(&self) -> Aabb {
+            match self {
+                Primitive::Sphere { center, radius } => {
+                    let r = Vec3::of(*radius, *radius, *radius);
+                    Aabb::of(center - r, center + r)
+                }
+                Primitive::Triangle(vertices) => {
+                    let mut bounds = Aabb::empty();
+                    for v in vertices {
+                        bounds.grow(*v);
+                    }
+                    bounds
+                }
+            }
+        }
+
+        fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<f32> {
+            match self {
+                Primitive::Sphere { center, radius } => {
+                    let oc = ray.base - center;
+                    let a = ray.dir.length_squared();
+                    let half_b = oc.dot(&ray.dir);
+                    let c = oc.length_squared() - radius * radius;
+                    let discriminant = half_b * half_b - a * c;
+                    if discriminant < 0.0 {
+                        return None;
+                    }
+                    let root = discriminant.sqrt();
+                    [(-half_b - root) / a, (-half_b + root) / a]
+                        .into_iter()
+                        .find(|t| t_min < *t && *t < t_max)
+                }
+                Primitive::Triangle([p0, p1, p2]) => {
+                    intersect_triangle(ray, p0, p1, p2, t_min, t_max).map(|(t, _)| t)
+                }
+            }
+        }
+    }
+
+    fn random_sphere(rng: &mut impl MyRng) -> Primitive {
+        Primitive::Sphere {
+            center: Vec3::random_in_range(-10.0, 10.0, rng),
+            radius: rng.random_f32_from_range(0.05, 1.0),
+        }
+    }
+
+    fn random_triangle(rng: &mut impl MyRng) -> Primitive {
+        let p0 = Vec3::random_in_range(-10.0, 10.0, rng);
+        Primitive::Triangle([
+            p0,
+            p0 + Vec3::random_in_range(-2.0, 2.0, rng),
+            p0 + Vec3::random_in_range(-2.0, 2.0, rng),
+        ])
+    }
+
+    /// The closest hit and its primitive by testing every primitive, the
+    /// lower index winning ties.
+    fn linear_scan(prims: &[Primitive], ray: &Ray) -> Option<(usize, f32)> {
+        let mut closest: Option<(usize, f32)> = None;
+        for (i, prim) in prims.iter().enumerate() {
+            if let Some(t) = prim.hit(ray, 0.001, f32::MAX)
+                && closest.is_none_or(|(_, best)| t < best)
+            {
+                closest = Some((i, t));
+            }
+        }
+        closest
+    }
+
+    /// Fires random rays from inside and outside the scene and compares
+    /// the BVH with a linear scan. Returns how many rays hit something.
+    fn assert_matches_linear_scan(prims: &[Primitive], rays: usize, rng: &mut impl MyRng) -> usize {
+        let bounds: Vec<Aabb> = prims.iter().map(Primitive::bounds).collect();
+        let bvh = Bvh::build(&bounds);
+        let mut hits = 0;
+        for _ in 0..rays {
+            let base = Vec3::random_in_range(-15.0, 15.0, rng);
+            let ray = Ray::new(base, Vec3::random_on_unit_sphere(rng));
+            let expected = linear_scan(prims, &ray);
+            let found = bvh.closest_hit(&ray, 0.001, f32::MAX, |i, t_max| {
+                prims[i].hit(&ray, 0.001, t_max).map(|t| (t, (i, t)))
+            });
+            assert_eq!(found, expected, "ray {ray:?}");
+            hits += found.is_some() as usize;
+        }
+        hits
+    }
+
+    fn depth(bvh: &Bvh, node: usize) -> usize {
+        let node_ref = &bvh.nodes[node];
+        if node_ref.count > 0 {
+            return 0;
+        }
+        1 + depth(bvh, node + 1).max(depth(bvh, node_ref.first as usize))
+    }
+
+    #[test]
+    fn random_spheres_and_triangles_match_linear_scan() {
+        let mut rng = MySmallRng::from_seed(1);
+        for size in [2, 3, 10, 100, 1000] {
+            let prims: Vec<Primitive> = (0..size)
+                .map(|i| {
+                    if i % 2 == 0 {
+                        random_sphere(&mut rng)
+                    } else {
+                        random_triangle(&mut rng)
+                    }
+                })
+                .collect();
+            let hits = assert_matches_linear_scan(&prims, 2000, &mut rng);
+            assert!(hits > 0, "no ray hit any of {size} primitives");
+        }
+    }
+
+    #[test]
+    fn single_primitive() {
+        let mut rng = MySmallRng::from_seed(2);
+        let prims = [Primitive::Sphere {
+            center: Vec3::zero(),
+            radius: 5.0,
+        }];
+        assert!(assert_matches_linear_scan(&prims, 500, &mut rng) > 0);
+        let prims = [random_triangle(&mut rng)];
+        assert_matches_linear_scan(&prims, 500, &mut rng);
+    }
+
+    #[test]
+    fn no_primitives() {
+        let bvh = Bvh::build(&[]);
+        let ray = Ray::new(Vec3::zero(), Vec3::of(0.0, 0.0, -1.0));
+        let hit: Option<()> = bvh.closest_hit(&ray, 0.001, f32::MAX, |_, _| Some((1.0, ())));
+        assert!(hit.is_none());
+    }
+
+    #[test]
+    fn equal_centroids() {
+        let mut rng = MySmallRng::from_seed(3);
+        // Nested spheres and triangles whose bounds share one centre.
+        let c = Vec3::zero();
+        let prims: Vec<Primitive> = (0..50)
+            .map(|i| {
+                let size = 0.2 * (i + 1) as f32;
+                if i % 2 == 0 {
+                    Primitive::Sphere {
+                        center: c,
+                        radius: size,
+                    }
+                } else {
+                    Primitive::Triangle([
+                        c + Vec3::of(-size, -size, -size),
+                        c + Vec3::of(size, -size, 0.0),
+                        c + Vec3::of(0.0, size, size),
+                    ])
+                }
+            })
+            .collect();
+        let bounds: Vec<Aabb> = prims.iter().map(Primitive::bounds).collect();
+        assert_eq!(Bvh::build(&bounds).nodes.len(), 1);
+        assert!(assert_matches_linear_scan(&prims, 2000, &mut rng) > 0);
+    }
+
+    #[test]
+    fn deeper_than_max_depth() {
+        let mut rng = MySmallRng::from_seed(4);
+        // Spheres at doubling distances along each axis. The largest
+        // centroids get bins of their own, so every split peels off a
+        // handful and the tree runs into MAX_DEPTH with many left.
+        let mut prims = vec![];
+        for axis in 0..3 {
+            for exponent in -100..50 {
+                let x = 2.0f32.powi(exponent);
+                let mut center = [0.0; 3];
+                center[axis] = x;
+                prims.push(Primitive::Sphere {
+                    center: Vec3::of(center[0], center[1], center[2]),
+                    radius: 0.25 * x,
+                });
+            }
+        }
+        let bounds: Vec<Aabb> = prims.iter().map(Primitive::bounds).collect();
+        assert!(depth(&Bvh::build(&bounds), 0) >= MAX_DEPTH);
+        assert!(assert_matches_linear_scan(&prims, 2000, &mut rng) > 0);
+    }
+}
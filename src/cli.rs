@@ -0,0 +1,360 @@
+use std::{error::Error, fmt::Display, path::PathBuf, str::FromStr};
+
+use raytrace::{
+    exr::ExrCompression,
+    image_io::ImageFormat,
+    render::RenderSettings,
+    scene::integrator::{
+        AmbientOcclusion, DEFAULT_MAX_BOUNCE_DEPTH, DEFAULT_ROULETTE_DEPTH, DebugView,
+        LightSampling, PathTracer, Whitted,
+    },
+};
+
+pub const USAGE: &str = "\
+Usage: raytrace [OPTIONS]
+
+Options:
+  --width <PIXELS>       Image width [default: 600]
+  --height <PIXELS>      Image height [default: width / (16 / 9)]
+  --spp <N>              Samples per pixel [default: 100]
+  --max-depth <N>        Maximum number of bounces per path
+                         [default: unlimited, 50 for whitted]
+  --roulette-depth <N>   Bounces before Russian roulette may end a path [default: 3]
+  --threads <N>          Number of render threads [default: 4]
+  --seed <N>             Seed for reproducible renders [default: random]
+  --integrator <NAME>    How to compute the image [default: path]
+                           path      path tracing
+                           nee       path tracing, sampling lights directly
+                           mis       path tracing, weighing light and material
+                                     samples by multiple importance sampling
+                           whitted   direct light and perfect mirrors only
+                           ao        ambient occlusion
+                           normals, depth, uv, material-id, albedo
+                                     show that property of the first hit
+  --ao-distance <D>      Occlusion range of ao [default: unlimited]
+  --scene <FILE>         Scene to render, .toml or .obj [default: built-in demo scene]
+  --output <FILE>        Output image, .png, .ppm, .hdr, .pfm or .exr [default: image.png]
+  --exr-compression <C>  Compression of .exr output, none or zip [default: zip]
+  --display              Also show the result with ImageMagick's display
+  -h, --help             Print this help";
+
+pub struct Args {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: i32,
+    pub settings: RenderSettings,
+    pub integrator: IntegratorChoice,
+    pub scene: Option<PathBuf>,
+    pub output: PathBuf,
+    pub exr_compression: ExrCompression,
+    pub display: bool,
+}
+
+pub enum IntegratorChoice {
+    Path(PathTracer),
+    Whitted(Whitted),
+    AmbientOcclusion(AmbientOcclusion),
+    Debug(DebugView),
+}
+
+pub enum Command {
+    Render(Args),
+    Help,
+}
+
+#[derive(Debug)]
+pub struct CliError {
+    message: String,
+}
+
+impl Error for CliError {}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+fn error(message: String) -> CliError {
+    CliError { message }
+}
+
+/// Parses a number and checks it against an inclusive range.
+fn number<T>(option: &str, value: &str, min: T, max: T) -> Result<T, CliError>
+where
+    T: FromStr + PartialOrd + Display,
+{
+    let parsed: T = value
+        .parse()
+        .map_err(|_| error(format!("{option} expects a whole number, got '{value}'")))?;
+    if parsed < min || parsed > max {
+        return Err(error(format!(
+            "{option} must be between {min} and {max}, got {value}"
+        )));
+    }
+    Ok(parsed)
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, CliError> {
+    let mut width: usize = 600;
+    let mut height: Option<usize> = None;
+    let mut samples_per_pixel: i32 = 100;
+    let mut settings = RenderSettings::default();
+    let mut integrator = String::from("path");
+    let mut max_depth = None;
+    let mut roulette_depth = DEFAULT_ROULETTE_DEPTH;
+    let mut ao_distance = f32::INFINITY;
+    let mut scene = None;
+    let mut output = PathBuf::from("image.png");
+    let mut exr_compression = ExrCompression::Zip;
+    let mut display = false;
+
+    let mut args = args.into_iter();
+    while let Some(option) = args.next() {
+        if option == "-h" || option == "--help" {
+            return Ok(Command::Help);
+        }
+        if option == "--display" {
+            display = true;
+            continue;
+        }
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| error(format!("{option} expects a value")))
+        };
+        match option.as_str() {
+            "--width" => width = number(&option, &value()?, 1, 1 << 16)?,
+            "--height" => height = Some(number(&option, &value()?, 1, 1 << 16)?),
+            "--spp" => samples_per_pixel = number(&option, &value()?, 1, 1 << 20)?,
+            "--max-depth" => max_depth = Some(number(&option, &value()?, 1, 10_000)?),
+            "--roulette-depth" => roulette_depth = number(&option, &value()?, 0, 10_000)?,
+            "--threads" => settings.n_threads = number(&option, &value()?, 1, 1024)?,
+            "--seed" => settings.seed = Some(number(&option, &value()?, 0, u64::MAX)?),
+            "--integrator" => integrator = value()?,
+            "--ao-distance" => {
+                let value = value()?;
+                ao_distance = match value.parse::<f32>() {
+                    Ok(distance) if distance > 0.0 => distance,
+                    _ => {
+                        return Err(error(format!(
+                            "{option} expects a positive distance, got '{value}'"
+                        )));
+                    }
+                }
+            }
+            "--scene" => {
+                let path = PathBuf::from(value()?);
+                if !path.is_file() {
+                    return Err(error(format!(
+                        "scene file '{}' does not exist",
+                        path.display()
+                    )));
+                }
+                scene = Some(path);
+            }
+            "--output" => {
+                let path = PathBuf::from(value()?);
+                ImageFormat::from_path(&path).map_err(|e| error(e.to_string()))?;
+                output = path;
+            }
+            "--exr-compression" => {
+                exr_compression = match value()?.as_str() {
+                    "none" => ExrCompression::None,
+                    "zip" => ExrCompression::Zip,
+                    other => {
+                        return Err(error(format!(
+                            "unknown EXR compression '{other}', expected none or zip"
+                        )));
+                    }
+                }
+            }
+            _ => return Err(error(format!("unknown option '{option}'"))),
+        }
+    }
+
+    let path = |light_sampling| {
+        IntegratorChoice::Path(PathTracer {
+            max_depth,
+            roulette_depth,
+            light_sampling,
+        })
+    };
+    let integrator = match integrator.as_str() {
+        "path" => path(LightSampling::None),
+        "nee" => path(LightSampling::NextEvent),
+        "mis" => path(LightSampling::MultipleImportance),
+        "whitted" => IntegratorChoice::Whitted(Whitted {
+            max_depth: max_depth.unwrap_or(DEFAULT_MAX_BOUNCE_DEPTH),
+        }),
+        "ao" => IntegratorChoice::AmbientOcclusion(AmbientOcclusion {
+            distance: ao_distance,
+        }),
+        "normals" => IntegratorChoice::Debug(DebugView::Normals),
+        "depth" => IntegratorChoice::Debug(DebugView::Depth),
+        "uv" => IntegratorChoice::Debug(DebugView::Uvs),
+        "material-id" => IntegratorChoice::Debug(DebugView::MaterialId),
+        "albedo" => IntegratorChoice::Debug(DebugView::Albedo),
+        other => {
+            return Err(error(format!(
+                "unknown integrator '{other}', see --help for the choices"
+            )));
+        }
+    };
+
+    let height = height.unwrap_or(((width as f64 / (16.0 / 9.0)) as usize).max(1));
+    Ok(Command::Render(Args {
+        width,
+        height,
+        samples_per_pixel,
+        settings,
+        integrator,
+        scene,
+        output,
+        exr_compression,
+        display,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_strs(args: &[&str]) -> Result<Command, CliError> {
+        parse(args.iter().map(|a| a.to_string()))
+    }
+
+    fn render_args(args: &[&str]) -> Args {
+        match parse_strs(args) {
+            Ok(Command::Render(args)) => args,
+            Ok(Command::Help) => panic!("{args:?} asked for help"),
+            Err(e) => panic!("{args:?}: {e}"),
+        }
+    }
+
+    fn rejection(args: &[&str]) -> String {
+        match parse_strs(args) {
+            Ok(_) => panic!("{args:?} accepted"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let args = render_args(&[]);
+        assert_eq!((args.width, args.height), (600, 337));
+        assert_eq!(args.samples_per_pixel, 100);
+        assert_eq!(args.output, PathBuf::from("image.png"));
+        assert_eq!(args.exr_compression, ExrCompression::Zip);
+        assert!(args.scene.is_none() && !args.display);
+        match args.integrator {
+            IntegratorChoice::Path(path) => {
+                assert_eq!(path.max_depth, None);
+                assert_eq!(path.roulette_depth, DEFAULT_ROULETTE_DEPTH);
+                assert_eq!(path.light_sampling, LightSampling::None);
+            }
+            _ => panic!("the default integrator is not the path tracer"),
+        }
+    }
+
+    #[test]
+    fn accepts_options() {
+        let args = render_args(&[
+            "--width",
+            "320",
+            "--height",
+            "200",
+            "--spp",
+            "16",
+            "--threads",
+            "2",
+            "--seed",
+            "7",
+            "--integrator",
+            "mis",
+            "--max-depth",
+            "12",
+            "--roulette-depth",
+            "0",
+            "--output",
+            "out.exr",
+            "--exr-compression",
+            "none",
+            "--display",
+        ]);
+        assert_eq!((args.width, args.height), (320, 200));
+        assert_eq!(args.samples_per_pixel, 16);
+        assert_eq!(args.settings.n_threads, 2);
+        assert_eq!(args.settings.seed, Some(7));
+        assert_eq!(args.output, PathBuf::from("out.exr"));
+        assert_eq!(args.exr_compression, ExrCompression::None);
+        assert!(args.display);
+        match args.integrator {
+            IntegratorChoice::Path(path) => {
+                assert_eq!(path.max_depth, Some(12));
+                assert_eq!(path.roulette_depth, 0);
+                assert_eq!(path.light_sampling, LightSampling::MultipleImportance);
+            }
+            _ => panic!("mis is not the path tracer"),
+        }
+    }
+
+    #[test]
+    fn picks_integrators() {
+        match render_args(&["--integrator", "whitted"]).integrator {
+            IntegratorChoice::Whitted(whitted) => {
+                assert_eq!(whitted.max_depth, DEFAULT_MAX_BOUNCE_DEPTH)
+            }
+            _ => panic!("whitted"),
+        }
+        match render_args(&["--integrator", "ao", "--ao-distance", "2.5"]).integrator {
+            IntegratorChoice::AmbientOcclusion(ao) => assert_eq!(ao.distance, 2.5),
+            _ => panic!("ao"),
+        }
+        match render_args(&["--integrator", "material-id"]).integrator {
+            IntegratorChoice::Debug(view) => assert_eq!(view, DebugView::MaterialId),
+            _ => panic!("material-id"),
+        }
+    }
+
+    #[test]
+    fn help() {
+        assert!(matches!(
+            parse_strs(&["--width", "10", "-h"]),
+            Ok(Command::Help)
+        ));
+        assert!(matches!(parse_strs(&["--help"]), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert_eq!(
+            rejection(&["--width", "0"]),
+            "--width must be between 1 and 65536, got 0"
+        );
+        assert_eq!(
+            rejection(&["--spp", "0"]),
+            "--spp must be between 1 and 1048576, got 0"
+        );
+        assert_eq!(
+            rejection(&["--spp", "many"]),
+            "--spp expects a whole number, got 'many'"
+        );
+        assert_eq!(rejection(&["--height"]), "--height expects a value");
+        assert_eq!(
+            rejection(&["--integrator", "bidirectional"]),
+            "unknown integrator 'bidirectional', see --help for the choices"
+        );
+        assert_eq!(
+            rejection(&["--exr-compression", "piz"]),
+            "unknown EXR compression 'piz', expected none or zip"
+        );
+        assert_eq!(
+            rejection(&["--ao-distance", "-1"]),
+            "--ao-distance expects a positive distance, got '-1'"
+        );
+        assert_eq!(
+            rejection(&["--frobnicate"]),
+            "unknown option '--frobnicate'"
+        );
+    }
+}
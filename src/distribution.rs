@@ -0,0 +1,110 @@
+//! Piecewise-constant distributions for importance sampling tabulated
+//! functions, like the brightness of an image.
+
+/// Density proportional to a non-negative step function over [0, 1].
+#[derive(Debug)]
+pub struct Distribution1D {
+    function: Vec<f32>,
+    /// `function.len() + 1` entries from 0 to 1.
+    cdf: Vec<f32>,
+    /// Mean of `function`.
+    integral: f32,
+}
+
+impl Distribution1D {
+    /// Functions that are zero everywhere are sampled uniformly.
+    pub fn new(function: Vec<f32>) -> Self {
+        let n = function.len();
+        let mut cdf = Vec::with_capacity(n + 1);
+        cdf.push(0.0);
+        // Summed in f64, long rows of small values would round away in f32.
+        let mut sum = 0.0f64;
+        for f in &function {
+            sum += f64::from(*f) / n as f64;
+            cdf.push(sum as f32);
+        }
+        let integral = sum as f32;
+        if integral > 0.0 {
+            for c in &mut cdf[1..] {
+                *c /= integral;
+            }
+        } else {
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f32 / n as f32;
+            }
+        }
+        cdf[n] = 1.0;
+        Self {
+            function,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    /// Maps `u` in [0, 1) to a point in [0, 1) with density `pdf`. Returns
+    /// the point, its density and the step it falls in.
+    pub fn sample(&self, u: f32) -> (f32, f32, usize) {
+        let n = self.function.len();
+        // The last step whose start is at most `u`, skipping empty steps.
+        let index = (self.cdf.partition_point(|c| *c <= u) - 1).min(n - 1);
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let offset = if width > 0.0 {
+            (u - self.cdf[index]) / width
+        } else {
+            0.0
+        };
+        let x = ((index as f32 + offset) / n as f32).min(1.0 - f32::EPSILON);
+        (x, self.pdf(index), index)
+    }
+
+    /// Density in step `index`.
+    pub fn pdf(&self, index: usize) -> f32 {
+        if self.integral > 0.0 {
+            self.function[index] / self.integral
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Density proportional to a non-negative function tabulated on a grid over
+/// [0, 1]², a row of the grid at a time.
+#[derive(Debug)]
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    /// Over the rows, by their integrals.
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// `function` holds `height` rows of `width` values each.
+    pub fn new(function: &[f32], width: usize, height: usize) -> Self {
+        let rows: Vec<Distribution1D> = function
+            .chunks_exact(width)
+            .take(height)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(rows.iter().map(|row| row.integral()).collect());
+        Self { rows, marginal }
+    }
+
+    /// Maps two numbers in [0, 1) to a point in [0, 1)² as `[x, y]`, `y`
+    /// picking the row, with its density.
+    pub fn sample(&self, u: [f32; 2]) -> ([f32; 2], f32) {
+        let (y, row_pdf, row) = self.marginal.sample(u[1]);
+        let (x, pdf, _) = self.rows[row].sample(u[0]);
+        ([x, y], row_pdf * pdf)
+    }
+
+    /// Density of `sample` returning `[x, y]`.
+    pub fn pdf(&self, [x, y]: [f32; 2]) -> f32 {
+        let step = |t: f32, n: usize| ((t * n as f32) as usize).min(n - 1);
+        let row = step(y, self.rows.len());
+        let row_distribution = &self.rows[row];
+        self.marginal.pdf(row) * row_distribution.pdf(step(x, row_distribution.function.len()))
+    }
+}
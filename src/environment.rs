@@ -0,0 +1,112 @@
+//! Light arriving from infinitely far away, from a panorama in
+//! equirectangular (latitude-longitude) projection. The top row of the
+//! image is straight up along y, the centre column looks along -z and
+//! columns further right turn towards +x.
+
+use std::f32::consts::PI;
+
+use crate::{
+    distribution::Distribution2D,
+    geo::{Transform, Vec3},
+    image_io::FloatImage,
+    random::MyRng,
+    render::Color,
+};
+
+#[derive(Debug)]
+pub struct EnvironmentMap {
+    image: FloatImage,
+    intensity: f32,
+    /// From the scene to the panorama, undoes the rotation.
+    to_map: Transform,
+    from_map: Transform,
+    /// Over the image, by luminance times the solid angle of the pixels.
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    /// `rotation` turns the panorama around the y axis, in degrees. The
+    /// image's colours are scaled by `intensity`.
+    pub fn new(image: FloatImage, rotation: f32, intensity: f32) -> Self {
+        let (width, height) = (image.width, image.height);
+        let mut weights = Vec::with_capacity(width * height);
+        for row in 0..height {
+            // Rows near the poles cover less of the sphere.
+            let sin_theta = (PI * (row as f32 + 0.5) / height as f32).sin();
+            for color in &image.pixels[row * width..(row + 1) * width] {
+                let luminance = 0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z;
+                weights.push(luminance.max(0.0) * sin_theta);
+            }
+        }
+        let from_map = Transform::rotate(&Vec3::of(0.0, 1.0, 0.0), rotation);
+        Self {
+            distribution: Distribution2D::new(&weights, width, height),
+            image,
+            intensity,
+            to_map: from_map.inverse(),
+            from_map,
+        }
+    }
+
+    /// Radiance arriving from direction `dir`, looking out of the scene.
+    pub fn radiance(&self, dir: &Vec3) -> Color {
+        let [u, v] = uv_of(&self.to_map.vector(dir).unit());
+        self.intensity * self.bilinear(u, v)
+    }
+
+    /// A unit direction picked by the brightness of the map, with its
+    /// density per solid angle. `None` for the poles, which the projection
+    /// squeezes into lines.
+    pub fn sample(&self, rng: &mut impl MyRng) -> Option<(Vec3, f32)> {
+        let ([u, v], pdf) = self
+            .distribution
+            .sample([rng.random_f32(), rng.random_f32()]);
+        let theta = PI * v;
+        let sin_theta = theta.sin();
+        if pdf <= 0.0 || sin_theta <= 0.0 {
+            return None;
+        }
+        let phi = 2.0 * PI * (u - 0.5);
+        let local = Vec3::of(sin_theta * phi.sin(), theta.cos(), -sin_theta * phi.cos());
+        // The map is a sphere of area 4 pi over a rectangle of 2 pi by pi.
+        Some((
+            self.from_map.vector(&local),
+            pdf / (2.0 * PI * PI * sin_theta),
+        ))
+    }
+
+    /// Density per solid angle of `sample` returning the unit vector `dir`.
+    pub fn pdf(&self, dir: &Vec3) -> f32 {
+        let local = self.to_map.vector(dir);
+        let sin_theta = (local.x * local.x + local.z * local.z).sqrt();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(uv_of(&local)) / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// Interpolates between pixel centres, wrapping around horizontally and
+    /// holding the top and bottom rows.
+    fn bilinear(&self, u: f32, v: f32) -> Color {
+        let (width, height) = (self.image.width, self.image.height);
+        let x = u * width as f32 - 0.5;
+        let y = (v * height as f32 - 0.5).clamp(0.0, (height - 1) as f32);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let column = |i: i64| i.rem_euclid(width as i64) as usize;
+        let row = |i: i64| (i.max(0) as usize).min(height - 1);
+        let pixel = |c: i64, r: i64| self.image.pixels[row(r) * width + column(c)];
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = (1.0 - fx) * pixel(x0, y0) + fx * pixel(x0 + 1, y0);
+        let bottom = (1.0 - fx) * pixel(x0, y0 + 1) + fx * pixel(x0 + 1, y0 + 1);
+        (1.0 - fy) * top + fy * bottom
+    }
+}
+
+/// Image coordinates of a unit direction in the map's space, `v` growing
+/// downwards from the top row.
+fn uv_of(dir: &Vec3) -> [f32; 2] {
+    let theta = dir.y.clamp(-1.0, 1.0).acos();
+    let phi = dir.x.atan2(-dir.z);
+    [0.5 + phi / (2.0 * PI), theta / PI]
+}
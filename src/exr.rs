@@ -0,0 +1,510 @@
+use std::io::{Read, Write};
+
+use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
+
+use crate::{
+    image_io::{self, FloatImage, ImageError},
+    render::Color,
+};
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+/// Version 2, single part scanline file without long names.
+const VERSION: [u8; 4] = [2, 0, 0, 0];
+
+const PIXEL_TYPE_HALF: i32 = 1;
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+const COMPRESSION_NONE: u8 = 0;
+/// ZIPS and ZIP only differ in the number of lines per block.
+const COMPRESSION_ZIPS: u8 = 2;
+const COMPRESSION_ZIP: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExrCompression {
+    None,
+    /// Zlib over 16 scanline blocks, with the byte reordering and delta
+    /// predictor from the OpenEXR reference implementation.
+    Zip,
+}
+
+impl ExrCompression {
+    fn id(self) -> u8 {
+        match self {
+            ExrCompression::None => COMPRESSION_NONE,
+            ExrCompression::Zip => COMPRESSION_ZIP,
+        }
+    }
+
+    fn lines_per_block(id: u8) -> Option<usize> {
+        match id {
+            COMPRESSION_NONE | COMPRESSION_ZIPS => Some(1),
+            COMPRESSION_ZIP => Some(16),
+            _ => None,
+        }
+    }
+}
+
+fn attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(kind.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+fn box2i(width: usize, height: usize) -> Vec<u8> {
+    [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect()
+}
+
+/// Writes RGB as 32 bit float channels. Channels are stored in alphabetical
+/// order (B, G, R) as the format requires.
+pub fn write_exr(
+    out: &mut impl Write,
+    width: usize,
+    height: usize,
+    pixels: &[Color],
+    compression: ExrCompression,
+) -> Result<(), ImageError> {
+    let mut header = vec![];
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&VERSION);
+
+    let mut channels = vec![];
+    for name in ["B", "G", "R"] {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        // pLinear and three reserved bytes, then x and y sampling.
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+    attribute(&mut header, "channels", "chlist", &channels);
+    attribute(
+        &mut header,
+        "compression",
+        "compression",
+        &[compression.id()],
+    );
+    attribute(&mut header, "dataWindow", "box2i", &box2i(width, height));
+    attribute(&mut header, "displayWindow", "box2i", &box2i(width, height));
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    let lines_per_block = ExrCompression::lines_per_block(compression.id()).unwrap();
+    let mut blocks = vec![];
+    for first_line in (0..height).step_by(lines_per_block) {
+        let last_line = (first_line + lines_per_block).min(height);
+        let mut raw = Vec::with_capacity((last_line - first_line) * width * 12);
+        for row in pixels[first_line * width..last_line * width].chunks(width) {
+            for channel in [|c: &Color| c.z, |c: &Color| c.y, |c: &Color| c.x] {
+                for c in row {
+                    raw.extend_from_slice(&channel(c).to_le_bytes());
+                }
+            }
+        }
+        let data = match compression {
+            ExrCompression::None => raw,
+            ExrCompression::Zip => {
+                let compressed = zip_compress(&raw)?;
+                // Readers treat blocks that didn't shrink as stored raw.
+                if compressed.len() < raw.len() {
+                    compressed
+                } else {
+                    raw
+                }
+            }
+        };
+        let mut block = Vec::with_capacity(data.len() + 8);
+        block.extend_from_slice(&(first_line as i32).to_le_bytes());
+        block.extend_from_slice(&(data.len() as i32).to_le_bytes());
+        block.extend_from_slice(&data);
+        blocks.push(block);
+    }
+
+    let mut offset = (header.len() + 8 * blocks.len()) as u64;
+    out.write_all(&header)?;
+    for block in blocks.iter() {
+        out.write_all(&offset.to_le_bytes())?;
+        offset += block.len() as u64;
+    }
+    for block in blocks.iter() {
+        out.write_all(block)?;
+    }
+    Ok(())
+}
+
+fn zip_compress(raw: &[u8]) -> Result<Vec<u8>, ImageError> {
+    // Split even and odd bytes, then store each byte as the difference to its
+    // predecessor. Both make float data much easier to compress.
+    let half = raw.len().div_ceil(2);
+    let mut reordered = vec![0u8; raw.len()];
+    for (i, b) in raw.iter().enumerate() {
+        reordered[if i % 2 == 0 { i / 2 } else { half + i / 2 }] = *b;
+    }
+    let mut previous = reordered.first().copied().unwrap_or(0);
+    for b in reordered.iter_mut().skip(1) {
+        let current = *b;
+        *b = current.wrapping_sub(previous).wrapping_add(128);
+        previous = current;
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&reordered)?;
+    Ok(encoder.finish()?)
+}
+
+fn zip_decompress(data: &[u8], expected_len: usize) -> Result<Vec<u8>, ImageError> {
+    let mut reordered = Vec::with_capacity(expected_len);
+    ZlibDecoder::new(data).read_to_end(&mut reordered)?;
+    if reordered.len() != expected_len {
+        return Err(ImageError::Decoding(format!(
+            "block decompressed to {} bytes, expected {expected_len}",
+            reordered.len()
+        )));
+    }
+    for i in 1..reordered.len() {
+        reordered[i] = reordered[i - 1]
+            .wrapping_add(reordered[i])
+            .wrapping_sub(128);
+    }
+    let half = expected_len.div_ceil(2);
+    Ok((0..expected_len)
+        .map(|i| reordered[if i % 2 == 0 { i / 2 } else { half + i / 2 }])
+        .collect())
+}
+
+fn half_to_f32(h: u16) -> f32 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((h >> 10) & 0x1f) as i32;
+    let mantissa = (h & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        31 if mantissa == 0.0 => f32::INFINITY,
+        31 => f32::NAN,
+        e => (1.0 + mantissa / 1024.0) * 2f32.powi(e - 15),
+    }
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ImageError> {
+        let end = self
+            .position
+            .checked_add(n)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| ImageError::Decoding("unexpected end of file".into()))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<&'a str, ImageError> {
+        let rest = self.data.get(self.position..).unwrap_or_default();
+        let Some(end) = rest.iter().position(|b| *b == 0) else {
+            return Err(ImageError::Decoding("unterminated string".into()));
+        };
+        let s = std::str::from_utf8(&rest[..end])
+            .map_err(|_| ImageError::Decoding("string is not valid UTF-8".into()))?;
+        self.position += end + 1;
+        Ok(s)
+    }
+
+    fn i32(&mut self) -> Result<i32, ImageError> {
+        let b = self.take(4)?;
+        Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// A byte count, stored as a signed 32 bit number.
+    fn size(&mut self) -> Result<usize, ImageError> {
+        let size = self.i32()?;
+        usize::try_from(size).map_err(|_| ImageError::Decoding(format!("negative size {size}")))
+    }
+
+    fn u64(&mut self) -> Result<u64, ImageError> {
+        let b = self.take(8)?;
+        Ok(u64::from_le_bytes(b.try_into().unwrap()))
+    }
+}
+
+struct Channel {
+    name: String,
+    pixel_type: i32,
+}
+
+impl Channel {
+    fn size(&self) -> usize {
+        if self.pixel_type == PIXEL_TYPE_HALF {
+            2
+        } else {
+            4
+        }
+    }
+}
+
+/// Reads single part scanline files with uncompressed, ZIPS or ZIP blocks and
+/// half or float R, G, B (or luminance only Y) channels.
+pub fn read_exr(input: &mut impl Read) -> Result<FloatImage, ImageError> {
+    let err = |message: &str| ImageError::Decoding(message.to_string());
+    let mut data = vec![];
+    input.read_to_end(&mut data)?;
+    let mut cursor = Cursor {
+        data: &data,
+        position: 0,
+    };
+    if cursor.take(4)? != MAGIC {
+        return Err(err("not an OpenEXR file"));
+    }
+    let version = cursor.take(4)?;
+    if version[0] != 2 || version[1] & 0x1a != 0 {
+        return Err(ImageError::Unsupported(
+            "only single part scanline OpenEXR files are supported".into(),
+        ));
+    }
+
+    let mut channels: Vec<Channel> = vec![];
+    let mut compression = None;
+    let mut data_window = None;
+    loop {
+        let name = cursor.string()?;
+        if name.is_empty() {
+            break;
+        }
+        let kind = cursor.string()?.to_string();
+        let size = cursor.size()?;
+        let value = cursor.take(size)?;
+        let mut value_cursor = Cursor {
+            data: value,
+            position: 0,
+        };
+        match (name, kind.as_str()) {
+            ("channels", "chlist") => loop {
+                let channel_name = value_cursor.string()?;
+                if channel_name.is_empty() {
+                    break;
+                }
+                let pixel_type = value_cursor.i32()?;
+                value_cursor.take(4)?;
+                let x_sampling = value_cursor.i32()?;
+                let y_sampling = value_cursor.i32()?;
+                if x_sampling != 1 || y_sampling != 1 {
+                    return Err(ImageError::Unsupported(
+                        "subsampled OpenEXR channels".into(),
+                    ));
+                }
+                if pixel_type != PIXEL_TYPE_HALF && pixel_type != PIXEL_TYPE_FLOAT {
+                    return Err(ImageError::Unsupported(
+                        "only half and float OpenEXR channels are supported".into(),
+                    ));
+                }
+                channels.push(Channel {
+                    name: channel_name.to_string(),
+                    pixel_type,
+                });
+            },
+            ("compression", "compression") => compression = Some(value_cursor.take(1)?[0]),
+            ("dataWindow", "box2i") => {
+                data_window = Some([
+                    value_cursor.i32()?,
+                    value_cursor.i32()?,
+                    value_cursor.i32()?,
+                    value_cursor.i32()?,
+                ])
+            }
+            _ => {}
+        }
+    }
+
+    let compression = compression.ok_or_else(|| err("missing compression attribute"))?;
+    let lines_per_block = ExrCompression::lines_per_block(compression).ok_or_else(|| {
+        ImageError::Unsupported(format!(
+            "OpenEXR compression {compression}, only uncompressed, ZIPS and ZIP are supported"
+        ))
+    })?;
+    let [x_min, y_min, x_max, y_max] = data_window.ok_or_else(|| err("missing dataWindow"))?;
+    if x_max < x_min || y_max < y_min {
+        return Err(err("empty data window"));
+    }
+    let width = (x_max as i64 - x_min as i64 + 1) as usize;
+    let height = (y_max as i64 - y_min as i64 + 1) as usize;
+    let find = |name: &str| channels.iter().position(|c| c.name == name);
+    let rgb = match (find("R"), find("G"), find("B"), find("Y")) {
+        (Some(r), Some(g), Some(b), _) => [r, g, b],
+        (_, _, _, Some(y)) => [y, y, y],
+        _ => return Err(err("image has neither R, G, B nor Y channels")),
+    };
+    let bytes_per_pixel: usize = channels.iter().map(|c| c.size()).sum();
+
+    let n_blocks = height.div_ceil(lines_per_block);
+    let offsets = (0..n_blocks)
+        .map(|_| cursor.u64())
+        .collect::<Result<Vec<u64>, ImageError>>()?;
+
+    let mut pixels = vec![Color::zero(); image_io::pixel_count(width, height)?];
+    for offset in offsets {
+        cursor.position = usize::try_from(offset)
+            .ok()
+            .filter(|offset| *offset <= data.len())
+            .ok_or_else(|| err("block offset past the end of the file"))?;
+        let first_line = i64::from(cursor.i32()?) - i64::from(y_min);
+        if first_line < 0 || first_line as usize >= height {
+            return Err(err("block refers to a line outside of the data window"));
+        }
+        let first_line = first_line as usize;
+        let lines = lines_per_block.min(height - first_line);
+        let size = cursor.size()?;
+        let block = cursor.take(size)?;
+        let expected_len = lines * width * bytes_per_pixel;
+        let raw = match compression {
+            // ZIP blocks that wouldn't shrink are stored uncompressed.
+            COMPRESSION_NONE => block.to_vec(),
+            _ if size == expected_len => block.to_vec(),
+            _ => zip_decompress(block, expected_len)?,
+        };
+
+        let mut line_cursor = Cursor {
+            data: &raw,
+            position: 0,
+        };
+        for y in first_line..first_line + lines {
+            let row = &mut pixels[y * width..(y + 1) * width];
+            for (index, channel) in channels.iter().enumerate() {
+                let values = line_cursor.take(width * channel.size())?;
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let value = if channel.pixel_type == PIXEL_TYPE_HALF {
+                        half_to_f32(u16::from_le_bytes([values[2 * x], values[2 * x + 1]]))
+                    } else {
+                        let b = &values[4 * x..4 * x + 4];
+                        f32::from_le_bytes([b[0], b[1], b[2], b[3]])
+                    };
+                    if index == rgb[0] {
+                        pixel.x = value;
+                    }
+                    if index == rgb[1] {
+                        pixel.y = value;
+                    }
+                    if index == rgb[2] {
+                        pixel.z = value;
+                    }
+                }
+            }
+        }
+    }
+    Ok(FloatImage {
+        width,
+        height,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::{MyRng, MySmallRng};
+
+    /// Smooth gradients that ZIP compresses, next to noise that it can't.
+    fn test_pixels(width: usize, height: usize) -> Vec<Color> {
+        let mut rng = MySmallRng::from_seed((width * 1000 + height) as u64);
+        (0..width * height)
+            .map(|i| {
+                let (x, y) = ((i % width) as f32, (i / width) as f32);
+                if y < height as f32 / 2.0 {
+                    Color::of(x, y, 0.5)
+                } else {
+                    Color::of(
+                        rng.random_f32_from_range(0.0, 1000.0),
+                        rng.random_f32(),
+                        rng.random_f32_from_range(-1.0, 1.0),
+                    )
+                }
+            })
+            .collect()
+    }
+
+    fn round_trip(compression: ExrCompression) {
+        // 37 and 17 lines leave partial 16 line blocks for ZIP.
+        for (width, height) in [(1, 1), (7, 3), (3, 17), (40, 37), (100, 64)] {
+            let pixels = test_pixels(width, height);
+            let mut file = vec![];
+            write_exr(&mut file, width, height, &pixels, compression).unwrap();
+            let image = read_exr(&mut file.as_slice()).unwrap();
+            assert_eq!((image.width, image.height), (width, height));
+            for (read, written) in image.pixels.iter().zip(&pixels) {
+                assert_eq!([read.x, read.y, read.z], [written.x, written.y, written.z]);
+            }
+        }
+    }
+
+    #[test]
+    fn uncompressed_round_trip() {
+        round_trip(ExrCompression::None);
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        round_trip(ExrCompression::Zip);
+    }
+
+    /// The file with the first occurrence of `from` replaced by `to`.
+    fn patched(file: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+        let at = file
+            .windows(from.len())
+            .position(|w| w == from)
+            .expect("pattern in file");
+        let mut patched = file.to_vec();
+        patched[at..at + to.len()].copy_from_slice(to);
+        patched
+    }
+
+    #[test]
+    fn rejects_unknown_compression() {
+        let mut file = vec![];
+        write_exr(&mut file, 2, 2, &test_pixels(2, 2), ExrCompression::None).unwrap();
+        // PIZ.
+        let file = patched(
+            &file,
+            b"compression\0compression\0\x01\0\0\0\0",
+            b"compression\0compression\0\x01\0\0\0\x04",
+        );
+        assert!(matches!(
+            read_exr(&mut file.as_slice()),
+            Err(ImageError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_negative_sizes() {
+        let mut file = vec![];
+        write_exr(&mut file, 2, 2, &test_pixels(2, 2), ExrCompression::None).unwrap();
+        let file = patched(
+            &file,
+            b"lineOrder\0lineOrder\0\x01\0\0\0",
+            b"lineOrder\0lineOrder\0\xff\xff\xff\xff",
+        );
+        assert!(matches!(
+            read_exr(&mut file.as_slice()),
+            Err(ImageError::Decoding(_))
+        ));
+    }
+}
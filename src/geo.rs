@@ -46,6 +46,21 @@ impl Vec3 {
         candidate
     }
 
+    /// Uniform point in the unit disk in the xy plane.
+    #[inline(always)]
+    pub fn random_in_unit_disk(rng: &mut impl MyRng) -> Self {
+        loop {
+            let candidate = Self::of(
+                rng.random_f32_from_range(-1.0, 1.0),
+                rng.random_f32_from_range(-1.0, 1.0),
+                0.0,
+            );
+            if candidate.length_squared() < 1.0 {
+                return candidate;
+            }
+        }
+    }
+
     #[inline(always)]
     pub fn random_on_unit_sphere(rng: &mut impl MyRng) -> Self {
         Self::random_in_unit_sphere(rng).unit()
@@ -100,7 +115,21 @@ impl Vec3 {
 
     #[inline(always)]
     pub fn near_zero(&self) -> bool {
-        return self.x.abs() < 1.0e-8 && self.y.abs() < 1.0e-8 && self.z.abs() < 1.0e-8;
+        self.x.abs() < 1.0e-8 && self.y.abs() < 1.0e-8 && self.z.abs() < 1.0e-8
+    }
+}
+
+impl ops::Index<usize> for Vec3 {
+    type Output = f32;
+
+    #[inline(always)]
+    fn index(&self, index: usize) -> &Self::Output {
+        match index {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 index out of range: {index}"),
+        }
     }
 }
 
@@ -312,3 +341,341 @@ impl ops::Div<&f32> for Vec3 {
         }
     }
 }
+
+/// Orthonormal basis with `z` along a given direction, for working in the
+/// coordinates of a surface.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub x: Vec3,
+    pub y: Vec3,
+    pub z: Vec3,
+}
+
+impl Frame {
+    /// `z` must be a unit vector. Without branches or singularities, see
+    /// Duff et al., "Building an Orthonormal Basis, Revisited" (JCGT 2017).
+    pub fn from_z(z: &Vec3) -> Self {
+        let sign = 1.0f32.copysign(z.z);
+        let a = -1.0 / (sign + z.z);
+        let b = z.x * z.y * a;
+        Self {
+            x: Vec3::of(1.0 + sign * z.x * z.x * a, sign * b, -sign * z.x),
+            y: Vec3::of(b, sign + z.y * z.y * a, -z.y),
+            z: *z,
+        }
+    }
+
+    /// `x` along `tangent` made perpendicular to the unit vector `z`. Falls
+    /// back to `from_z` for tangents that are zero or parallel to `z`.
+    pub fn from_z_and_tangent(z: &Vec3, tangent: &Vec3) -> Self {
+        let x = tangent - tangent.dot(z) * z;
+        if x.length_squared() < 1.0e-12 {
+            return Self::from_z(z);
+        }
+        let x = x.unit();
+        Self {
+            x,
+            y: z.cross(&x),
+            z: *z,
+        }
+    }
+
+    pub fn to_local(&self, v: &Vec3) -> Vec3 {
+        Vec3::of(v.dot(&self.x), v.dot(&self.y), v.dot(&self.z))
+    }
+
+    pub fn to_world(&self, v: &Vec3) -> Vec3 {
+        v.x * self.x + v.y * self.y + v.z * self.z
+    }
+}
+
+/// Affine transformation as a 4x4 matrix in row-major order, together with
+/// its inverse. Points are columns, `then` composes left to right.
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+    matrix: [[f32; 4]; 4],
+    inverse: [[f32; 4]; 4],
+}
+
+const IDENTITY: [[f32; 4]; 4] = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Self {
+            matrix: IDENTITY,
+            inverse: IDENTITY,
+        }
+    }
+
+    /// `None` if the matrix can't be inverted. The last row is expected to
+    /// be `[0, 0, 0, 1]`, projections are not supported.
+    pub fn from_matrix(matrix: [[f32; 4]; 4]) -> Option<Self> {
+        Some(Self {
+            matrix,
+            inverse: invert(&matrix)?,
+        })
+    }
+
+    pub fn translate(offset: &Vec3) -> Self {
+        let mut matrix = IDENTITY;
+        let mut inverse = IDENTITY;
+        for axis in 0..3 {
+            matrix[axis][3] = offset[axis];
+            inverse[axis][3] = -offset[axis];
+        }
+        Self { matrix, inverse }
+    }
+
+    /// `None` if a factor is zero.
+    pub fn scale(factors: &Vec3) -> Option<Self> {
+        if factors.x == 0.0 || factors.y == 0.0 || factors.z == 0.0 {
+            return None;
+        }
+        let mut matrix = IDENTITY;
+        let mut inverse = IDENTITY;
+        for axis in 0..3 {
+            matrix[axis][axis] = factors[axis];
+            inverse[axis][axis] = 1.0 / factors[axis];
+        }
+        Some(Self { matrix, inverse })
+    }
+
+    /// Counter-clockwise rotation by `degrees` looking down the unit vector
+    /// `axis`.
+    pub fn rotate(axis: &Vec3, degrees: f32) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let a = axis;
+        let mut matrix = IDENTITY;
+        let rotation = [
+            [
+                cos + a.x * a.x * (1.0 - cos),
+                a.x * a.y * (1.0 - cos) - a.z * sin,
+                a.x * a.z * (1.0 - cos) + a.y * sin,
+            ],
+            [
+                a.y * a.x * (1.0 - cos) + a.z * sin,
+                cos + a.y * a.y * (1.0 - cos),
+                a.y * a.z * (1.0 - cos) - a.x * sin,
+            ],
+            [
+                a.z * a.x * (1.0 - cos) - a.y * sin,
+                a.z * a.y * (1.0 - cos) + a.x * sin,
+                cos + a.z * a.z * (1.0 - cos),
+            ],
+        ];
+        // Rotations are orthogonal, the inverse is the transpose.
+        let mut inverse = IDENTITY;
+        for row in 0..3 {
+            for column in 0..3 {
+                matrix[row][column] = rotation[row][column];
+                inverse[column][row] = rotation[row][column];
+            }
+        }
+        Self { matrix, inverse }
+    }
+
+    /// `self` followed by `next`.
+    pub fn then(&self, next: &Transform) -> Self {
+        Self {
+            matrix: multiply(&next.matrix, &self.matrix),
+            inverse: multiply(&self.inverse, &next.inverse),
+        }
+    }
+
+    pub fn inverse(&self) -> Self {
+        Self {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
+    pub fn point(&self, p: &Vec3) -> Vec3 {
+        let m = &self.matrix;
+        Vec3::of(
+            m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+            m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+            m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3],
+        )
+    }
+
+    /// Directions ignore the translation.
+    pub fn vector(&self, v: &Vec3) -> Vec3 {
+        let m = &self.matrix;
+        Vec3::of(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+
+    /// Normals go through the inverse transpose to stay perpendicular to
+    /// the surface. Not normalized.
+    pub fn normal(&self, n: &Vec3) -> Vec3 {
+        let m = &self.inverse;
+        Vec3::of(
+            m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        )
+    }
+
+    /// Factor by which lengths grow on average, the cube root of the volume
+    /// change.
+    pub fn scale_factor(&self) -> f32 {
+        let m = &self.matrix;
+        let determinant = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+        determinant.abs().cbrt()
+    }
+}
+
+fn multiply(a: &[[f32; 4]; 4], b: &[[f32; 4]; 4]) -> [[f32; 4]; 4] {
+    let mut product = [[0.0; 4]; 4];
+    for (row, product_row) in product.iter_mut().enumerate() {
+        for (column, entry) in product_row.iter_mut().enumerate() {
+            *entry = (0..4).map(|k| a[row][k] * b[k][column]).sum();
+        }
+    }
+    product
+}
+
+/// Gauss-Jordan elimination with partial pivoting.
+fn invert(matrix: &[[f32; 4]; 4]) -> Option<[[f32; 4]; 4]> {
+    let mut m = *matrix;
+    let mut inverse = IDENTITY;
+    for column in 0..4 {
+        let pivot =
+            (column..4).max_by(|a, b| m[*a][column].abs().total_cmp(&m[*b][column].abs()))?;
+        if m[pivot][column].abs() < 1.0e-12 {
+            return None;
+        }
+        m.swap(column, pivot);
+        inverse.swap(column, pivot);
+        let scale = 1.0 / m[column][column];
+        for k in 0..4 {
+            m[column][k] *= scale;
+            inverse[column][k] *= scale;
+        }
+        for row in 0..4 {
+            if row == column {
+                continue;
+            }
+            let factor = m[row][column];
+            for k in 0..4 {
+                m[row][k] -= factor * m[column][k];
+                inverse[row][k] -= factor * inverse[column][k];
+            }
+        }
+    }
+    Some(inverse)
+}
+
+/// Scale, then rotation around the x, y and z axes in that order, then
+/// translation. Rotations are in degrees.
+#[derive(Clone, Copy, Debug)]
+pub struct Pose {
+    pub scale: Vec3,
+    pub rotate: Vec3,
+    pub translate: Vec3,
+}
+
+impl Default for Pose {
+    fn default() -> Self {
+        Self {
+            scale: Vec3::of(1.0, 1.0, 1.0),
+            rotate: Vec3::zero(),
+            translate: Vec3::zero(),
+        }
+    }
+}
+
+impl Pose {
+    /// `None` if a scale factor is zero.
+    pub fn transform(&self) -> Option<Transform> {
+        let mut transform = Transform::scale(&self.scale)?;
+        for (axis, unit) in [
+            Vec3::of(1.0, 0.0, 0.0),
+            Vec3::of(0.0, 1.0, 0.0),
+            Vec3::of(0.0, 0.0, 1.0),
+        ]
+        .iter()
+        .enumerate()
+        {
+            transform = transform.then(&Transform::rotate(unit, self.rotate[axis]));
+        }
+        Some(transform.then(&Transform::translate(&self.translate)))
+    }
+
+    fn lerp(&self, other: &Pose, f: f32) -> Pose {
+        Pose {
+            scale: (1.0 - f) * self.scale + f * other.scale,
+            rotate: (1.0 - f) * self.rotate + f * other.rotate,
+            translate: (1.0 - f) * self.translate + f * other.translate,
+        }
+    }
+}
+
+/// Poses at increasing times, interpolated linearly in between and held
+/// before the first and after the last.
+#[derive(Clone, Debug)]
+pub struct Keyframes {
+    keys: Vec<(f32, Pose)>,
+}
+
+impl Keyframes {
+    /// `None` unless there is at least one key, the times increase strictly
+    /// and the scale factors along each axis all have the same, non-zero
+    /// sign. Flipping signs would pass through a flat, singular transform.
+    pub fn new(keys: Vec<(f32, Pose)>) -> Option<Self> {
+        let (_, first) = keys.first()?;
+        let same_sign = |a: f32, b: f32| a != 0.0 && a.signum() == b.signum();
+        for pair in keys.windows(2) {
+            if pair[0].0 >= pair[1].0 {
+                return None;
+            }
+        }
+        for (_, pose) in &keys {
+            for axis in 0..3 {
+                if !same_sign(pose.scale[axis], first.scale[axis]) {
+                    return None;
+                }
+            }
+        }
+        Some(Self { keys })
+    }
+
+    pub fn pose_at(&self, time: f32) -> Pose {
+        let next = self.keys.partition_point(|(t, _)| *t <= time);
+        if next == 0 {
+            return self.keys[0].1;
+        }
+        let (t0, pose0) = &self.keys[next - 1];
+        let Some((t1, pose1)) = self.keys.get(next) else {
+            return *pose0;
+        };
+        pose0.lerp(pose1, (time - t0) / (t1 - t0))
+    }
+
+    pub fn at(&self, time: f32) -> Transform {
+        self.pose_at(time)
+            .transform()
+            .expect("keyframe scales never pass through zero")
+    }
+
+    /// The times of the keys.
+    pub fn times(&self) -> impl Iterator<Item = f32> + '_ {
+        self.keys.iter().map(|(t, _)| *t)
+    }
+}
@@ -0,0 +1,133 @@
+//! Values sampled on a regular 3D lattice, like the density of smoke from a
+//! fluid simulation.
+
+use std::path::Path;
+
+use crate::geo::Vec3;
+
+/// Voxel values with `x` varying fastest, then `y`, then `z`. Each value
+/// sits at the centre of its voxel in the unit cube.
+#[derive(Debug)]
+pub struct VoxelGrid {
+    resolution: [usize; 3],
+    values: Vec<f32>,
+    max: f32,
+}
+
+impl VoxelGrid {
+    /// Negative and non-finite values become zero. `None` if `values`
+    /// doesn't hold one value per voxel.
+    pub fn new(resolution: [usize; 3], values: Vec<f32>) -> Option<Self> {
+        let count = resolution
+            .iter()
+            .try_fold(1usize, |n, r| n.checked_mul(*r))?;
+        if count == 0 || values.len() != count {
+            return None;
+        }
+        let values: Vec<f32> = values
+            .into_iter()
+            .map(|v| if v.is_finite() { v.max(0.0) } else { 0.0 })
+            .collect();
+        let max = values.iter().copied().fold(0.0, f32::max);
+        Some(Self {
+            resolution,
+            values,
+            max,
+        })
+    }
+
+    /// Evaluates `f` at the centre of every voxel, given in the unit cube.
+    pub fn from_fn(resolution: [usize; 3], f: impl Fn(&Vec3) -> f32) -> Option<Self> {
+        let [nx, ny, nz] = resolution;
+        let mut values = Vec::with_capacity(nx * ny * nz);
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    let centre = |i: usize, n: usize| (i as f32 + 0.5) / n as f32;
+                    values.push(f(&Vec3::of(centre(x, nx), centre(y, ny), centre(z, nz))));
+                }
+            }
+        }
+        Self::new(resolution, values)
+    }
+
+    /// Reads a file of little-endian 32 bit floats without a header, in the
+    /// order of `VoxelGrid`.
+    pub fn load_raw(path: impl AsRef<Path>, resolution: [usize; 3]) -> std::io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let expected = resolution.iter().product::<usize>().saturating_mul(4);
+        if bytes.len() != expected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "expected {expected} bytes for {} x {} x {} voxels, found {}",
+                    resolution[0],
+                    resolution[1],
+                    resolution[2],
+                    bytes.len()
+                ),
+            ));
+        }
+        let values = bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        Self::new(resolution, values).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "a grid needs voxels")
+        })
+    }
+
+    /// Applies `f` to every value.
+    pub fn map(self, f: impl Fn(f32) -> f32) -> Self {
+        let values = self.values.into_iter().map(f).collect();
+        Self::new(self.resolution, values).expect("the resolution stays the same")
+    }
+
+    /// The largest value, which bounds every lookup.
+    pub fn max(&self) -> f32 {
+        self.max
+    }
+
+    /// Trilinear interpolation at `p` in the unit cube, zero outside of it.
+    /// Between the outermost voxel centres and the faces the edge values
+    /// continue.
+    pub fn value(&self, p: &Vec3) -> f32 {
+        if !(0.0..=1.0).contains(&p.x) || !(0.0..=1.0).contains(&p.y) || !(0.0..=1.0).contains(&p.z)
+        {
+            return 0.0;
+        }
+        // Continuous voxel coordinates with voxel centres at integers.
+        let position = [p.x, p.y, p.z];
+        let mut low = [0; 3];
+        let mut fraction = [0.0; 3];
+        for axis in 0..3 {
+            let n = self.resolution[axis];
+            let x = (position[axis] * n as f32 - 0.5).clamp(0.0, (n - 1) as f32);
+            low[axis] = (x as usize).min(n.saturating_sub(2));
+            fraction[axis] = x - low[axis] as f32;
+        }
+        let mut sum = 0.0;
+        for corner in 0..8 {
+            let mut weight = 1.0;
+            let mut index = [0; 3];
+            for axis in 0..3 {
+                let high = corner & (1 << axis) != 0;
+                index[axis] = (low[axis] + high as usize).min(self.resolution[axis] - 1);
+                weight *= if high {
+                    fraction[axis]
+                } else {
+                    1.0 - fraction[axis]
+                };
+            }
+            if weight > 0.0 {
+                sum += weight * self.at(index);
+            }
+        }
+        sum
+    }
+
+    fn at(&self, [x, y, z]: [usize; 3]) -> f32 {
+        let [nx, ny, _] = self.resolution;
+        self.values[(z * ny + y) * nx + x]
+    }
+}
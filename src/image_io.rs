@@ -0,0 +1,665 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{exr, render::Color};
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(std::io::Error),
+    UnsupportedFormat(PathBuf),
+    /// A well-formed file using a feature the reader doesn't handle.
+    Unsupported(String),
+    Encoding(Box<dyn Error + 'static>),
+    Decoding(String),
+}
+
+impl From<std::io::Error> for ImageError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl Error for ImageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ImageError::Io(cause) => Some(cause),
+            ImageError::UnsupportedFormat(_)
+            | ImageError::Unsupported(_)
+            | ImageError::Decoding(_) => None,
+            ImageError::Encoding(cause) => Some(cause.as_ref()),
+        }
+    }
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::Io(cause) => write!(f, "{cause}"),
+            ImageError::UnsupportedFormat(path) => write!(
+                f,
+                "can't tell the image format of '{}' from its extension",
+                path.display()
+            ),
+            ImageError::Unsupported(message) => write!(f, "unsupported image: {message}"),
+            ImageError::Encoding(cause) => write!(f, "encoding failed: {cause}"),
+            ImageError::Decoding(message) => write!(f, "decoding failed: {message}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Ppm,
+    Png,
+    /// Radiance RGBE.
+    Hdr,
+    /// Portable float map.
+    Pfm,
+    /// Scanline OpenEXR with 32 bit float channels.
+    Exr,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &Path) -> Result<Self, ImageError> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("ppm") => Ok(Self::Ppm),
+            Some("png") => Ok(Self::Png),
+            Some("hdr") => Ok(Self::Hdr),
+            Some("pfm") => Ok(Self::Pfm),
+            Some("exr") => Ok(Self::Exr),
+            _ => Err(ImageError::UnsupportedFormat(path.to_owned())),
+        }
+    }
+}
+
+/// Binary (P6) PPM from tightly packed 8 bit RGB rows.
+pub fn write_ppm(
+    out: &mut impl Write,
+    width: usize,
+    height: usize,
+    rgb: &[u8],
+) -> Result<(), ImageError> {
+    write!(out, "P6\n{width} {height}\n255\n")?;
+    out.write_all(rgb)?;
+    Ok(())
+}
+
+/// 8 bit RGB PNG from tightly packed rows.
+pub fn write_png(
+    out: &mut impl Write,
+    width: usize,
+    height: usize,
+    rgb: &[u8],
+) -> Result<(), ImageError> {
+    let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    // The pixel values are already gamma 2 encoded, see `Color::to_rgb8`.
+    encoder.set_source_gamma(png::ScaledFloat::new(0.5));
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| ImageError::Encoding(Box::new(e)))?;
+    writer
+        .write_image_data(rgb)
+        .map_err(|e| ImageError::Encoding(Box::new(e)))?;
+    writer
+        .finish()
+        .map_err(|e| ImageError::Encoding(Box::new(e)))
+}
+
+pub(crate) fn create(path: &Path) -> Result<BufWriter<File>, ImageError> {
+    Ok(BufWriter::new(File::create(path)?))
+}
+
+/// Linear float pixels, rows top to bottom.
+#[derive(Clone)]
+pub struct FloatImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
+}
+
+impl std::fmt::Debug for FloatImage {
+    // Only the size, the pixels would drown out everything else.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FloatImage")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Reads an image into linear floats, the decoder is picked from the
+/// extension. Integer formats are taken to be sRGB encoded unless the file
+/// says otherwise.
+pub fn load(path: impl AsRef<Path>) -> Result<FloatImage, ImageError> {
+    let path = path.as_ref();
+    let format = ImageFormat::from_path(path)?;
+    let mut input = BufReader::new(File::open(path)?);
+    match format {
+        ImageFormat::Ppm => read_ppm(&mut input),
+        ImageFormat::Png => read_png(&mut input),
+        ImageFormat::Hdr => read_hdr(&mut input),
+        ImageFormat::Pfm => read_pfm(&mut input),
+        ImageFormat::Exr => exr::read_exr(&mut input),
+    }
+}
+
+fn decoding_error(message: impl Into<String>) -> ImageError {
+    ImageError::Decoding(message.into())
+}
+
+/// Inverse of the sRGB transfer function, `value` in [0, 1].
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Checked `width * height`, so absurd headers don't overflow or allocate
+/// all memory.
+pub(crate) fn pixel_count(width: usize, height: usize) -> Result<usize, ImageError> {
+    if width == 0 || height == 0 {
+        return Err(decoding_error("image is empty"));
+    }
+    width
+        .checked_mul(height)
+        .filter(|count| *count <= 1 << 28)
+        .ok_or_else(|| decoding_error(format!("image of {width}x{height} pixels is too large")))
+}
+
+/// Truncated files fail with a message naming the missing part instead of
+/// the generic "failed to fill whole buffer".
+fn read_exact_or(input: &mut impl Read, buf: &mut [u8], what: &str) -> Result<(), ImageError> {
+    input.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => decoding_error(format!("file ends inside the {what}")),
+        _ => ImageError::Io(e),
+    })
+}
+
+/// Plain (P3) or binary (P6) PPM with up to 16 bits per sample.
+pub fn read_ppm(input: &mut impl BufRead) -> Result<FloatImage, ImageError> {
+    // Magic, width, height and maximum value are separated by whitespace and
+    // comments, a single whitespace character precedes binary data.
+    let mut tokens: Vec<String> = vec![];
+    let mut token = String::new();
+    let mut in_comment = false;
+    while tokens.len() < 4 {
+        let mut byte = [0u8; 1];
+        read_exact_or(input, &mut byte, "header")?;
+        let c = byte[0];
+        if in_comment {
+            in_comment = c != b'\n' && c != b'\r';
+        } else if c == b'#' {
+            in_comment = true;
+        } else if c.is_ascii_whitespace() {
+            if !token.is_empty() {
+                tokens.push(std::mem::take(&mut token));
+            }
+        } else {
+            token.push(c as char);
+            if token.len() > 20 {
+                return Err(decoding_error("malformed header"));
+            }
+        }
+    }
+    let binary = match tokens[0].as_str() {
+        "P6" => true,
+        "P3" => false,
+        magic => {
+            return Err(decoding_error(format!(
+                "'{magic}' is not a PPM signature, only P3 and P6 are supported"
+            )));
+        }
+    };
+    let number = |token: &str, what: &str| {
+        token
+            .parse::<usize>()
+            .map_err(|_| decoding_error(format!("invalid {what} '{token}'")))
+    };
+    let width = number(&tokens[1], "width")?;
+    let height = number(&tokens[2], "height")?;
+    let max_value = number(&tokens[3], "maximum value")?;
+    if !(1..=65535).contains(&max_value) {
+        return Err(decoding_error(format!(
+            "maximum value {max_value} is outside of 1 to 65535"
+        )));
+    }
+    let sample_count = 3 * pixel_count(width, height)?;
+
+    let samples: Vec<usize> = if binary {
+        let bytes_per_sample = if max_value < 256 { 1 } else { 2 };
+        let mut data = vec![0u8; sample_count * bytes_per_sample];
+        read_exact_or(input, &mut data, "pixel data")?;
+        data.chunks_exact(bytes_per_sample)
+            .map(|b| match b {
+                [v] => *v as usize,
+                [high, low] => (*high as usize) << 8 | *low as usize,
+                _ => unreachable!(),
+            })
+            .collect()
+    } else {
+        let mut text = String::new();
+        input
+            .read_to_string(&mut text)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::InvalidData => decoding_error("pixel data is not text"),
+                _ => ImageError::Io(e),
+            })?;
+        let samples = text
+            .split_ascii_whitespace()
+            .take(sample_count)
+            .map(|token| number(token, "sample"))
+            .collect::<Result<Vec<_>, _>>()?;
+        if samples.len() < sample_count {
+            return Err(decoding_error(format!(
+                "file ends after {} of {sample_count} samples",
+                samples.len()
+            )));
+        }
+        samples
+    };
+    if let Some(sample) = samples.iter().find(|sample| **sample > max_value) {
+        return Err(decoding_error(format!(
+            "sample {sample} exceeds the maximum value {max_value}"
+        )));
+    }
+
+    let linear = |sample: usize| srgb_to_linear(sample as f32 / max_value as f32);
+    let pixels = samples
+        .chunks_exact(3)
+        .map(|rgb| Color::of(linear(rgb[0]), linear(rgb[1]), linear(rgb[2])))
+        .collect();
+    Ok(FloatImage {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// PNG of any colour type and bit depth, alpha is ignored. Honours the file's
+/// gamma, sRGB is assumed when it doesn't state one.
+pub fn read_png(input: &mut impl BufRead) -> Result<FloatImage, ImageError> {
+    let png_error = |e: png::DecodingError| match e {
+        png::DecodingError::IoError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            decoding_error("file ends inside the image data")
+        }
+        png::DecodingError::IoError(e) => ImageError::Io(e),
+        e => decoding_error(e.to_string()),
+    };
+    let mut decoder = png::Decoder::new(input);
+    // Palettes and bit depths below 8 become plain 8 bit samples.
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().map_err(png_error)?;
+    let info = reader.info();
+    let gamma = match (info.srgb, info.source_gamma) {
+        (None, Some(gamma)) => Some(gamma.into_value()),
+        _ => None,
+    };
+    let mut data = vec![0u8; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut data).map_err(png_error)?;
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let count = pixel_count(width, height)?;
+
+    let channels = frame.color_type.samples();
+    let samples_per_row = match frame.bit_depth {
+        png::BitDepth::Eight => frame.line_size,
+        png::BitDepth::Sixteen => frame.line_size / 2,
+        depth => return Err(decoding_error(format!("unexpected bit depth {depth:?}"))),
+    };
+    let data = &data[..frame.buffer_size()];
+    let samples: Vec<f32> = match frame.bit_depth {
+        png::BitDepth::Eight => data.iter().map(|v| *v as f32 / 255.0).collect(),
+        _ => data
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as f32 / 65535.0)
+            .collect(),
+    };
+    let linear = |value: f32| match gamma {
+        Some(gamma) => value.powf(1.0 / gamma),
+        None => srgb_to_linear(value),
+    };
+    let mut pixels = Vec::with_capacity(count);
+    for row in samples.chunks_exact(samples_per_row) {
+        pixels.extend(row.chunks_exact(channels).take(width).map(|v| {
+            let (r, g, b) = match v {
+                [y] | [y, _] => (*y, *y, *y),
+                [r, g, b, ..] => (*r, *g, *b),
+                _ => unreachable!(),
+            };
+            Color::of(linear(r), linear(g), linear(b))
+        }));
+    }
+    Ok(FloatImage {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// Shared exponent encoding: the largest component decides the exponent,
+/// all three mantissas are stored with 8 bits relative to it. Infinities
+/// saturate to the largest encodable value and NaNs become 0.
+fn to_rgbe(c: &Color) -> [u8; 4] {
+    let finite = |x: f32| if x.is_nan() { 0.0 } else { x.min(f32::MAX) };
+    let c = Color::of(finite(c.x), finite(c.y), finite(c.z));
+    let v = c.x.max(c.y).max(c.z);
+    if v < 1.0e-32 {
+        return [0, 0, 0, 0];
+    }
+    // v = m * 2^e with m in [0.5, 1)
+    let mut e = v.log2().floor() as i32 + 1;
+    if v / 2f32.powi(e) >= 1.0 {
+        e += 1;
+    }
+    // The stored exponent is e + 128 in a byte, larger values saturate.
+    let e = e.min(127);
+    let scale = 256.0 / 2f32.powi(e);
+    let byte = |x: f32| (x.max(0.0) * scale).min(255.0) as u8;
+    [byte(c.x), byte(c.y), byte(c.z), (e + 128) as u8]
+}
+
+fn from_rgbe(rgbe: [u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::zero();
+    }
+    let f = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+    Color::of(
+        (rgbe[0] as f32 + 0.5) * f,
+        (rgbe[1] as f32 + 0.5) * f,
+        (rgbe[2] as f32 + 0.5) * f,
+    )
+}
+
+/// Radiance RGBE with flat (uncompressed) scanlines.
+pub fn write_hdr(
+    out: &mut impl Write,
+    width: usize,
+    height: usize,
+    pixels: &[Color],
+) -> Result<(), ImageError> {
+    write!(
+        out,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {height} +X {width}\n"
+    )?;
+    for c in pixels {
+        out.write_all(&to_rgbe(c))?;
+    }
+    Ok(())
+}
+
+/// Radiance RGBE, accepts flat and run length encoded scanlines.
+pub fn read_hdr(input: &mut impl BufRead) -> Result<FloatImage, ImageError> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(decoding_error("missing '#?' signature of a Radiance file"));
+    }
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Err(decoding_error("header ended before the resolution line"));
+        }
+        let header_line = line.trim_end();
+        if header_line.is_empty() {
+            break;
+        }
+        if let Some(format) = header_line.strip_prefix("FORMAT=")
+            && format != "32-bit_rle_rgbe"
+        {
+            return Err(ImageError::Unsupported(format!("pixel format {format}")));
+        }
+    }
+    line.clear();
+    input.read_line(&mut line)?;
+    let resolution: Vec<&str> = line.split_whitespace().collect();
+    let (height, width) = match resolution[..] {
+        ["-Y", h, "+X", w] => (
+            h.parse::<usize>()
+                .map_err(|_| decoding_error("invalid height"))?,
+            w.parse::<usize>()
+                .map_err(|_| decoding_error("invalid width"))?,
+        ),
+        _ => {
+            return Err(ImageError::Unsupported(format!(
+                "resolution line '{}', only '-Y h +X w' is supported",
+                line.trim_end()
+            )));
+        }
+    };
+    let mut pixels = Vec::with_capacity(pixel_count(width, height)?);
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        read_hdr_scanline(input, &mut scanline)?;
+        pixels.extend(scanline.iter().map(|rgbe| from_rgbe(*rgbe)));
+    }
+    Ok(FloatImage {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn read_hdr_scanline(input: &mut impl Read, scanline: &mut [[u8; 4]]) -> Result<(), ImageError> {
+    let width = scanline.len();
+    let mut first = [0u8; 4];
+    read_exact_or(input, &mut first, "pixel data")?;
+    let is_rle =
+        (8..0x8000).contains(&width) && first[0] == 2 && first[1] == 2 && first[2] & 0x80 == 0;
+    if !is_rle {
+        scanline[0] = first;
+        for pixel in scanline[1..].iter_mut() {
+            read_exact_or(input, pixel, "pixel data")?;
+        }
+        return Ok(());
+    }
+    if ((first[2] as usize) << 8 | first[3] as usize) != width {
+        return Err(decoding_error(
+            "run length encoded scanline has the wrong width",
+        ));
+    }
+    // Each of the four components is stored separately as runs and dumps.
+    for component in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let mut count = [0u8; 1];
+            read_exact_or(input, &mut count, "pixel data")?;
+            let count = count[0] as usize;
+            if count > 128 {
+                let run = count - 128;
+                if x + run > width {
+                    return Err(decoding_error("run overflows the scanline"));
+                }
+                let mut value = [0u8; 1];
+                read_exact_or(input, &mut value, "pixel data")?;
+                for pixel in scanline[x..x + run].iter_mut() {
+                    pixel[component] = value[0];
+                }
+                x += run;
+            } else {
+                if count == 0 || x + count > width {
+                    return Err(decoding_error("invalid dump in scanline"));
+                }
+                let mut values = [0u8; 128];
+                read_exact_or(input, &mut values[..count], "pixel data")?;
+                for (pixel, value) in scanline[x..x + count].iter_mut().zip(values) {
+                    pixel[component] = value;
+                }
+                x += count;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Portable float map, little endian. Rows are stored bottom to top.
+pub fn write_pfm(
+    out: &mut impl Write,
+    width: usize,
+    height: usize,
+    pixels: &[Color],
+) -> Result<(), ImageError> {
+    write!(out, "PF\n{width} {height}\n-1.0\n")?;
+    for row in pixels.chunks(width).rev() {
+        for c in row {
+            for v in [c.x, c.y, c.z] {
+                out.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Portable float map, colour (`PF`) or greyscale (`Pf`), either byte order.
+pub fn read_pfm(input: &mut impl BufRead) -> Result<FloatImage, ImageError> {
+    let mut header = String::new();
+    // Magic, dimensions and scale are whitespace separated and end with a
+    // single whitespace character before the binary data.
+    let mut tokens: Vec<String> = vec![];
+    while tokens.len() < 4 {
+        let mut byte = [0u8; 1];
+        read_exact_or(input, &mut byte, "header")?;
+        if byte[0].is_ascii_whitespace() {
+            if !header.is_empty() {
+                tokens.push(std::mem::take(&mut header));
+            }
+        } else {
+            header.push(byte[0] as char);
+            if header.len() > 20 {
+                return Err(decoding_error("malformed header"));
+            }
+        }
+    }
+    let channels = match tokens[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        magic => return Err(decoding_error(format!("'{magic}' is not a PFM signature"))),
+    };
+    let width: usize = tokens[1]
+        .parse()
+        .map_err(|_| decoding_error("invalid width"))?;
+    let height: usize = tokens[2]
+        .parse()
+        .map_err(|_| decoding_error("invalid height"))?;
+    let scale: f32 = tokens[3]
+        .parse()
+        .map_err(|_| decoding_error("invalid scale"))?;
+    let little_endian = scale < 0.0;
+
+    let mut data = vec![0u8; pixel_count(width, height)? * channels * 4];
+    read_exact_or(input, &mut data, "pixel data")?;
+    let values: Vec<f32> = data
+        .chunks_exact(4)
+        .map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            if little_endian {
+                f32::from_le_bytes(b)
+            } else {
+                f32::from_be_bytes(b)
+            }
+        })
+        .collect();
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in values.chunks(width * channels).rev() {
+        pixels.extend(row.chunks_exact(channels).map(|v| match v {
+            [r, g, b] => Color::of(*r, *g, *b),
+            [y] => Color::of(*y, *y, *y),
+            _ => unreachable!(),
+        }));
+    }
+    Ok(FloatImage {
+        width,
+        height,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::{MyRng, MySmallRng};
+
+    fn test_pixels(width: usize, height: usize) -> Vec<Color> {
+        let mut rng = MySmallRng::from_seed((width * 1000 + height) as u64);
+        (0..width * height)
+            .map(|i| match i % 4 {
+                0 => Color::zero(),
+                1 => Color::of(1.0, 0.5, 0.25),
+                _ => Color::of(
+                    rng.random_f32_from_range(0.0, 100.0),
+                    rng.random_f32_from_range(0.0, 1.0),
+                    rng.random_f32_from_range(0.0, 0.01),
+                ),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pfm_round_trip() {
+        for (width, height) in [(1, 1), (7, 3), (3, 17), (40, 37)] {
+            let pixels = test_pixels(width, height);
+            let mut file = vec![];
+            write_pfm(&mut file, width, height, &pixels).unwrap();
+            let image = read_pfm(&mut file.as_slice()).unwrap();
+            assert_eq!((image.width, image.height), (width, height));
+            for (read, written) in image.pixels.iter().zip(&pixels) {
+                assert_eq!([read.x, read.y, read.z], [written.x, written.y, written.z]);
+            }
+        }
+    }
+
+    #[test]
+    fn hdr_round_trip() {
+        for (width, height) in [(1, 1), (7, 3), (3, 17), (40, 37)] {
+            let pixels = test_pixels(width, height);
+            let mut file = vec![];
+            write_hdr(&mut file, width, height, &pixels).unwrap();
+            let image = read_hdr(&mut file.as_slice()).unwrap();
+            assert_eq!((image.width, image.height), (width, height));
+            for (read, written) in image.pixels.iter().zip(&pixels) {
+                // Channels share an exponent and keep 8 bits of mantissa,
+                // relative to the largest one.
+                let largest = written.x.max(written.y).max(written.z);
+                for (r, w) in [
+                    (read.x, written.x),
+                    (read.y, written.y),
+                    (read.z, written.z),
+                ] {
+                    assert!((r - w).abs() <= largest / 128.0, "read {r}, wrote {w}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hdr_saturates_huge_and_non_finite_pixels() {
+        let pixels = [
+            Color::of(f32::INFINITY, 1.0, 0.0),
+            Color::of(f32::MAX, f32::MAX, f32::MAX),
+            Color::of(f32::NAN, f32::NEG_INFINITY, 2.0),
+        ];
+        let mut file = vec![];
+        write_hdr(&mut file, 3, 1, &pixels).unwrap();
+        let image = read_hdr(&mut file.as_slice()).unwrap();
+        let largest = 2f32.powi(127);
+        for c in &image.pixels[..2] {
+            assert!(c.x.is_finite() && c.x >= 0.99 * largest, "read {}", c.x);
+        }
+        // Small channels keep 8 bits relative to the largest, as elsewhere.
+        let c = image.pixels[2];
+        for (r, w) in [(c.x, 0.0), (c.y, 0.0), (c.z, 2.0)] {
+            assert!((r - w).abs() <= 2.0 / 128.0, "read {r}, wrote {w}");
+        }
+    }
+}
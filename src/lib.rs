@@ -0,0 +1,16 @@
+pub mod bvh;
+pub mod distribution;
+pub mod environment;
+pub mod exr;
+pub mod geo;
+pub mod grid;
+pub mod image_io;
+pub mod medium;
+pub mod mesh;
+pub mod microfacet;
+pub mod obj;
+pub mod principled;
+pub mod random;
+pub mod render;
+pub mod scene;
+pub mod texture;
@@ -1,50 +1,125 @@
-use std::error::Error;
+use std::{error::Error, path::Path, process::ExitCode};
 
 use cpu_time::ProcessTime;
-use render::Color;
-use scene::Material;
+use raytrace::{
+    geo, render,
+    render::Color,
+    scene::{
+        self, Material,
+        file::{CameraDescription, SceneDescription},
+    },
+};
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let aspect_ratio = 16.0 / 9.0;
-    let image_width: usize = 600;
-    let image_height: usize = (image_width as f64 / aspect_ratio) as usize;
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<ExitCode, Box<dyn Error>> {
+    let args = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli::Command::Render(args)) => args,
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::USAGE);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Err(e) => {
+            eprintln!("error: {e}\n\n{}", cli::USAGE);
+            return Ok(ExitCode::from(2));
+        }
+    };
+
+    let mut img = render::Image::empty(args.width, args.height, args.samples_per_pixel);
+
+    let SceneDescription { scene, camera } = match &args.scene {
+        Some(path) => load_scene(path)?,
+        None => SceneDescription {
+            scene: demo_scene(),
+            camera: CameraDescription::default(),
+        },
+    };
+    let camera = camera.to_camera(args.width as f32 / args.height as f32);
+
+    let start = ProcessTime::now();
+    let settings = &args.settings;
+    match args.integrator {
+        cli::IntegratorChoice::Path(integrator) => {
+            render::render(&camera, &scene, &integrator, &mut img, settings)
+        }
+        cli::IntegratorChoice::Whitted(integrator) => {
+            render::render(&camera, &scene, &integrator, &mut img, settings)
+        }
+        cli::IntegratorChoice::AmbientOcclusion(integrator) => {
+            render::render(&camera, &scene, &integrator, &mut img, settings)
+        }
+        cli::IntegratorChoice::Debug(view) => {
+            render::render(&camera, &scene, &view, &mut img, settings)
+        }
+    }
+    println!(
+        "Raytracing took {:?}ms of cpu time.",
+        start.elapsed().as_millis()
+    );
+
+    img.save(&args.output, args.exr_compression)?;
+    if args.display {
+        img.write_to_display_process()?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
 
-    let mut img = render::Image::empty(image_width, image_height);
+/// Loads a TOML scene description, or a bare OBJ file seen from the default camera.
+fn load_scene(path: &Path) -> Result<SceneDescription, Box<dyn Error>> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => Ok(scene::file::load(path)?),
+        Some("obj") => {
+            let mut scene = scene::Scene::new();
+            let default_material = Material::Lambertian {
+                albedo: Color::of(0.8, 0.8, 0.8).into(),
+            };
+            scene.add_obj(path, default_material)?;
+            Ok(SceneDescription {
+                scene,
+                camera: CameraDescription::default(),
+            })
+        }
+        _ => Err(format!(
+            "unsupported scene file '{}', expected .toml or .obj",
+            path.display()
+        )
+        .into()),
+    }
+}
 
+fn demo_scene() -> scene::Scene {
     let mut scene = scene::Scene::new();
 
     let material_ground = Material::Lambertian {
-        albedo: Color::of(0.8, 0.8, 0.0),
+        albedo: Color::of(0.8, 0.8, 0.0).into(),
     };
     let material_center = Material::Lambertian {
-        albedo: Color::of(0.1, 0.2, 0.5),
+        albedo: Color::of(0.1, 0.2, 0.5).into(),
     };
     let material_left = Material::Dieletric {
         index_of_refraction: 1.5,
+        absorption: Color::zero(),
+        roughness: 0.0,
     };
     let material_right = Material::Metal {
-        albedo: Color::of(0.8, 0.6, 0.2),
+        albedo: Color::of(0.8, 0.6, 0.2).into(),
         fuzz: 1.0,
     };
 
     scene.add_sphere(geo::Vec3::of(0.0, -100.5, -1.0), 100.0, material_ground);
     scene.add_sphere(geo::Vec3::of(0.0, 0.0, -1.0), 0.5, material_center);
-    scene.add_sphere(geo::Vec3::of(-1.0, 0.0, -1.0), 0.5, material_left);
+    scene.add_sphere(geo::Vec3::of(-1.0, 0.0, -1.0), 0.5, material_left.clone());
     scene.add_sphere(geo::Vec3::of(-1.0, 0.0, -1.0), -0.4, material_left);
     scene.add_sphere(geo::Vec3::of(1.0, 0.0, -1.0), 0.5, material_right);
-
-    let start = ProcessTime::now();
-    render::render(&render::Camera::default(), &scene, &mut img);
-    println!(
-        "Raytracing took {:?}ms of cpu time.",
-        start.elapsed().as_millis()
-    );
-
-    img.write_to_display_process()?;
-    Ok(())
+    scene
 }
 
-mod geo;
-mod random;
-mod render;
-mod scene;
+mod cli;
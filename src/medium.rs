@@ -0,0 +1,309 @@
+//! Participating media, like fog and smoke, that absorb and scatter light
+//! throughout a volume instead of at surfaces. Coefficients are per unit of
+//! distance, directions point the way light travels.
+
+use std::{f32::consts::PI, sync::Arc};
+
+use crate::{
+    bvh::Aabb,
+    geo::{Frame, Vec3},
+    grid::VoxelGrid,
+    random::MyRng,
+    render::{Color, Ray},
+};
+
+/// Distribution of the directions light scatters into, after Henyey and
+/// Greenstein (1941).
+#[derive(Debug, Clone, Copy)]
+pub struct HenyeyGreenstein {
+    /// Mean cosine of the scattering angle, in (-1, 1). Positive values
+    /// scatter forwards, negative ones back, zero in all directions alike.
+    g: f32,
+}
+
+impl HenyeyGreenstein {
+    /// `g` is clamped away from -1 and 1, where all light would go one way.
+    pub fn new(g: f32) -> Self {
+        Self {
+            g: g.clamp(-0.99, 0.99),
+        }
+    }
+
+    /// Density per solid angle of light travelling along the unit vector
+    /// `dir_in` leaving along the unit vector `dir_out`. Also the
+    /// probability density of `sample`.
+    pub fn value(&self, dir_in: &Vec3, dir_out: &Vec3) -> f32 {
+        let g = self.g;
+        let denominator = 1.0 + g * g - 2.0 * g * dir_in.dot(dir_out);
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.max(0.0).sqrt())
+    }
+
+    /// A unit direction for light arriving along the unit vector `dir_in`,
+    /// with density `value`.
+    pub fn sample(&self, dir_in: &Vec3, rng: &mut impl MyRng) -> Vec3 {
+        let g = self.g;
+        let u = rng.random_f32();
+        let cos_theta = if g.abs() < 1.0e-3 {
+            1.0 - 2.0 * u
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+            ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng.random_f32();
+        let local = Vec3::of(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        Frame::from_z(dir_in).to_world(&local)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Medium {
+    /// The same density everywhere.
+    Homogeneous {
+        absorption: Color,
+        scattering: Color,
+        phase: HenyeyGreenstein,
+    },
+    /// Density varying over a box. The box stays where it is in instances.
+    Grid(GridMedium),
+}
+
+/// Outcome of `Medium::sample`.
+pub struct MediumSample {
+    /// Ray parameter of the point where light scatters, `None` if it gets
+    /// through.
+    pub scattered_at: Option<f32>,
+    /// Multiplies the throughput, keeps the estimate unbiased. Includes the
+    /// scattering coefficient at the scattering point.
+    pub weight: Color,
+    /// Light the medium emits towards the ray's base, to be weighted by the
+    /// throughput before `weight`.
+    pub emitted: Color,
+}
+
+/// Density from a voxel grid stretched over an axis-aligned box, zero
+/// outside of it. Coefficients are per unit of density.
+#[derive(Debug, Clone)]
+pub struct GridMedium {
+    density: Arc<VoxelGrid>,
+    bounds: Aabb,
+    absorption: Color,
+    scattering: Color,
+    /// Radiance emitted per unit of distance and density, glowing like
+    /// fire.
+    emission: Color,
+    phase: HenyeyGreenstein,
+    /// Bounds the extinction everywhere, tentative collisions are sampled
+    /// with it.
+    majorant: f32,
+}
+
+impl GridMedium {
+    pub fn new(
+        density: Arc<VoxelGrid>,
+        bounds: Aabb,
+        absorption: Color,
+        scattering: Color,
+        emission: Color,
+        phase: HenyeyGreenstein,
+    ) -> Self {
+        let extinction = absorption + scattering;
+        let mut majorant = density.max() * extinction.x.max(extinction.y).max(extinction.z);
+        // Media that only glow still need collisions to collect the glow.
+        if majorant <= 0.0 && !emission.near_zero() {
+            majorant = density.max();
+        }
+        Self {
+            density,
+            bounds,
+            absorption,
+            scattering,
+            emission,
+            phase,
+            majorant,
+        }
+    }
+
+    fn density_at(&self, p: &Vec3) -> f32 {
+        let size = self.bounds.max - self.bounds.min;
+        let local = p - self.bounds.min;
+        self.density.value(&Vec3::of(
+            local.x / size.x,
+            local.y / size.y,
+            local.z / size.z,
+        ))
+    }
+
+    /// Ray parameters where `ray` enters and leaves the box, within
+    /// `[0, t_max]`.
+    fn overlap(&self, ray: &Ray, t_max: f32) -> Option<(f32, f32)> {
+        let (mut t0, mut t1) = (0.0f32, t_max);
+        for axis in 0..3 {
+            let inverse = 1.0 / ray.dir[axis];
+            let a = (self.bounds.min[axis] - ray.base[axis]) * inverse;
+            let b = (self.bounds.max[axis] - ray.base[axis]) * inverse;
+            // min and max skip the NaN of rays lying in a slab's plane.
+            t0 = t0.max(a.min(b));
+            t1 = t1.min(a.max(b));
+        }
+        (t0 < t1).then_some((t0, t1))
+    }
+
+    /// Ray parameter of the next tentative collision after `t`, from a
+    /// homogeneous medium as dense as the majorant.
+    fn next_collision(&self, ray: &Ray, t: f32, rng: &mut impl MyRng) -> f32 {
+        t - (1.0 - rng.random_f32()).ln() / (self.majorant * ray.dir.length())
+    }
+
+    /// Delta tracking: tentative collisions are scattering, absorption or
+    /// null collisions with probabilities from the mean of the colour
+    /// channels. The weights make up for coloured media.
+    fn sample(&self, ray: &Ray, t_max: f32, rng: &mut impl MyRng) -> MediumSample {
+        let mut sample = MediumSample {
+            scattered_at: None,
+            weight: Color::of(1.0, 1.0, 1.0),
+            emitted: Color::zero(),
+        };
+        let Some((mut t, t_end)) = self.overlap(ray, t_max) else {
+            return sample;
+        };
+        if self.majorant <= 0.0 {
+            return sample;
+        }
+        loop {
+            t = self.next_collision(ray, t, rng);
+            if t >= t_end {
+                return sample;
+            }
+            let density = self.density_at(&ray.at(t));
+            sample.emitted += (density / self.majorant) * sample.weight * self.emission;
+            let scattering = density * self.scattering;
+            let null = Color::of(self.majorant, self.majorant, self.majorant)
+                - density * (self.absorption + self.scattering);
+            let (scatter_probability, null_probability) = (
+                mean(&scattering) / self.majorant,
+                mean(&null) / self.majorant,
+            );
+            let u = rng.random_f32();
+            if u < scatter_probability {
+                sample.scattered_at = Some(t);
+                sample.weight = sample.weight * scattering / mean(&scattering);
+                return sample;
+            } else if u < scatter_probability + null_probability {
+                sample.weight = sample.weight * null / mean(&null);
+            } else {
+                sample.weight = Color::zero();
+                return sample;
+            }
+        }
+    }
+
+    /// Ratio tracking: the product of the null collision probabilities at
+    /// tentative collisions.
+    fn transmittance(&self, ray: &Ray, t_max: f32, rng: &mut impl MyRng) -> Color {
+        let mut transmittance = Color::of(1.0, 1.0, 1.0);
+        let Some((mut t, t_end)) = self.overlap(ray, t_max) else {
+            return transmittance;
+        };
+        if self.majorant <= 0.0 {
+            return transmittance;
+        }
+        loop {
+            t = self.next_collision(ray, t, rng);
+            if t >= t_end {
+                return transmittance;
+            }
+            let extinction = self.density_at(&ray.at(t)) * (self.absorption + self.scattering);
+            let null = Color::of(1.0, 1.0, 1.0) - extinction / self.majorant;
+            transmittance = transmittance * null;
+            // Russian roulette ends long walks through dense media.
+            let largest = transmittance.x.max(transmittance.y).max(transmittance.z);
+            if largest < 0.1 {
+                if rng.random_f32() >= largest {
+                    return Color::zero();
+                }
+                transmittance /= largest;
+            }
+        }
+    }
+}
+
+impl Medium {
+    pub fn phase(&self) -> &HenyeyGreenstein {
+        match self {
+            Self::Homogeneous { phase, .. } => phase,
+            Self::Grid(grid) => &grid.phase,
+        }
+    }
+
+    /// Samples where light travelling along `ray` is scattered before
+    /// `t_max`.
+    pub fn sample(&self, ray: &Ray, t_max: f32, rng: &mut impl MyRng) -> MediumSample {
+        match self {
+            Self::Homogeneous {
+                absorption,
+                scattering,
+                ..
+            } => {
+                let extinction = absorption + *scattering;
+                let length = ray.dir.length();
+                let max_distance = t_max * length;
+                // Distances follow one colour channel's extinction, weighed
+                // against all three by the average of their densities.
+                let channel = ((rng.random_f32() * 3.0) as usize).min(2);
+                let distance = -(1.0 - rng.random_f32()).ln() / extinction[channel];
+                let transmittance = beer_lambert(&extinction, distance.min(max_distance));
+                let (scattered_at, weight) =
+                    if distance < max_distance && mean(&(extinction * transmittance)) > 0.0 {
+                        let density = mean(&(extinction * transmittance));
+                        (
+                            Some(distance / length),
+                            transmittance * *scattering / density,
+                        )
+                    } else if mean(&transmittance) > 0.0 {
+                        (None, transmittance / mean(&transmittance))
+                    } else {
+                        (None, Color::zero())
+                    };
+                MediumSample {
+                    scattered_at,
+                    weight,
+                    emitted: Color::zero(),
+                }
+            }
+            Self::Grid(grid) => grid.sample(ray, t_max, rng),
+        }
+    }
+
+    /// Fraction of light that gets along `ray` from its base up to `t_max`,
+    /// an unbiased estimate for grids.
+    pub fn transmittance(&self, ray: &Ray, t_max: f32, rng: &mut impl MyRng) -> Color {
+        match self {
+            Self::Homogeneous {
+                absorption,
+                scattering,
+                ..
+            } => beer_lambert(&(absorption + *scattering), t_max * ray.dir.length()),
+            Self::Grid(grid) => grid.transmittance(ray, t_max, rng),
+        }
+    }
+}
+
+fn beer_lambert(extinction: &Color, distance: f32) -> Color {
+    let transmittance = |sigma: f32| {
+        if sigma == 0.0 {
+            1.0
+        } else {
+            (-sigma * distance).exp()
+        }
+    };
+    Color::of(
+        transmittance(extinction.x),
+        transmittance(extinction.y),
+        transmittance(extinction.z),
+    )
+}
+
+fn mean(color: &Color) -> f32 {
+    (color.x + color.y + color.z) / 3.0
+}
@@ -0,0 +1,269 @@
+use crate::{
+    bvh::{Aabb, Bvh},
+    geo::Vec3,
+    random::MyRng,
+    render::Ray,
+};
+
+/// Indexed triangle mesh. All attribute buffers are indexed by the same
+/// vertex index, triangles refer to vertices by index.
+#[derive(Debug)]
+pub struct Mesh {
+    positions: Vec<Vec3>,
+    normals: Option<Vec<Vec3>>,
+    uvs: Option<Vec<[f32; 2]>>,
+    triangles: Vec<[u32; 3]>,
+    bvh: Bvh,
+    /// Running sum of the triangle areas, for picking triangles by area.
+    area_cdf: Vec<f32>,
+}
+
+pub struct MeshHit {
+    pub t: f32,
+    pub p: Vec3,
+    pub geometric_normal: Vec3,
+    pub shading_normal: Vec3,
+    pub uv: [f32; 2],
+    /// Texture coordinates per unit of length on the triangle, on average.
+    pub uv_per_length: f32,
+    /// Direction in which `u` grows, not normalized.
+    pub tangent: Vec3,
+}
+
+impl Mesh {
+    /// # Panics
+    ///
+    /// If a triangle refers to a vertex past the end of `positions`.
+    pub fn new(positions: Vec<Vec3>, triangles: Vec<[u32; 3]>) -> Self {
+        assert!(
+            triangles
+                .iter()
+                .flatten()
+                .all(|i| (*i as usize) < positions.len()),
+            "triangle refers to a vertex out of range"
+        );
+        let bounds: Vec<Aabb> = triangles
+            .iter()
+            .map(|[a, b, c]| {
+                let mut bounds = Aabb::of(positions[*a as usize], positions[*b as usize]);
+                bounds.grow(positions[*c as usize]);
+                bounds
+            })
+            .collect();
+        let area_cdf = triangles
+            .iter()
+            .scan(0.0, |sum, [a, b, c]| {
+                let [p0, p1, p2] = [a, b, c].map(|i| positions[*i as usize]);
+                *sum += triangle_area(&p0, &p1, &p2);
+                Some(*sum)
+            })
+            .collect();
+        Self {
+            bvh: Bvh::build(&bounds),
+            area_cdf,
+            positions,
+            normals: None,
+            uvs: None,
+            triangles,
+        }
+    }
+
+    /// # Panics
+    ///
+    /// If there isn't exactly one normal per vertex.
+    pub fn with_normals(mut self, normals: Vec<Vec3>) -> Self {
+        assert_eq!(normals.len(), self.positions.len(), "one normal per vertex");
+        self.normals = Some(normals);
+        self
+    }
+
+    /// # Panics
+    ///
+    /// If there isn't exactly one uv per vertex.
+    pub fn with_uvs(mut self, uvs: Vec<[f32; 2]>) -> Self {
+        assert_eq!(uvs.len(), self.positions.len(), "one uv per vertex");
+        self.uvs = Some(uvs);
+        self
+    }
+
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    pub fn normals(&self) -> Option<&[Vec3]> {
+        self.normals.as_deref()
+    }
+
+    pub fn uvs(&self) -> Option<&[[f32; 2]]> {
+        self.uvs.as_deref()
+    }
+
+    pub fn triangles(&self) -> &[[u32; 3]] {
+        &self.triangles
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let mut bounds = Aabb::empty();
+        for p in self.positions.iter() {
+            bounds.grow(*p);
+        }
+        bounds
+    }
+
+    pub fn area(&self) -> f32 {
+        self.area_cdf.last().copied().unwrap_or(0.0)
+    }
+
+    /// Uniformly distributed point on the surface and the geometric normal
+    /// there.
+    ///
+    /// # Panics
+    ///
+    /// If the mesh has no triangles.
+    pub fn sample_point(&self, rng: &mut impl MyRng) -> (Vec3, Vec3) {
+        let target = rng.random_f32() * self.area();
+        let triangle = self
+            .area_cdf
+            .partition_point(|sum| *sum <= target)
+            .min(self.triangles.len() - 1);
+        let [p0, p1, p2] = self.vertices(triangle);
+        (
+            sample_triangle(&p0, &p1, &p2, rng),
+            (p1 - p0).cross(&(p2 - p0)).unit(),
+        )
+    }
+
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<MeshHit> {
+        let (triangle, t, b) = self.bvh.closest_hit(ray, t_min, t_max, |i, t_max| {
+            let [p0, p1, p2] = self.vertices(i);
+            intersect_triangle(ray, &p0, &p1, &p2, t_min, t_max).map(|(t, b)| (t, (i, t, b)))
+        })?;
+
+        let [i0, i1, i2] = self.triangles[triangle].map(|i| i as usize);
+        let [p0, p1, p2] = self.vertices(triangle);
+        let geometric_normal = (p1 - p0).cross(&(p2 - p0)).unit();
+        let shading_normal = match &self.normals {
+            Some(n) => (b[0] * n[i0] + b[1] * n[i1] + b[2] * n[i2]).unit(),
+            None => geometric_normal,
+        };
+        let (e1, e2) = (p1 - p0, p2 - p0);
+        let (uv, uv_area, tangent) = match &self.uvs {
+            Some(uv) => {
+                let edge = |i: usize| [uv[i][0] - uv[i0][0], uv[i][1] - uv[i0][1]];
+                let (d1, d2) = (edge(i1), edge(i2));
+                let determinant = d1[0] * d2[1] - d1[1] * d2[0];
+                // Solve p - p0 = u dp/du + v dp/dv on both edges.
+                let tangent = if determinant.abs() > 1.0e-12 {
+                    (d2[1] * e1 - d1[1] * e2) / determinant
+                } else {
+                    e1
+                };
+                (
+                    [
+                        b[0] * uv[i0][0] + b[1] * uv[i1][0] + b[2] * uv[i2][0],
+                        b[0] * uv[i0][1] + b[1] * uv[i1][1] + b[2] * uv[i2][1],
+                    ],
+                    0.5 * determinant.abs(),
+                    tangent,
+                )
+            }
+            None => ([b[1], b[2]], 0.5, e1),
+        };
+        Some(MeshHit {
+            t,
+            p: b[0] * p0 + b[1] * p1 + b[2] * p2,
+            geometric_normal,
+            shading_normal,
+            uv,
+            uv_per_length: (uv_area / triangle_area(&p0, &p1, &p2)).sqrt(),
+            tangent,
+        })
+    }
+
+    fn vertices(&self, triangle: usize) -> [Vec3; 3] {
+        self.triangles[triangle].map(|i| self.positions[i as usize])
+    }
+}
+
+/// Watertight ray/triangle intersection (Woop, Benthin and Wald, JCGT 2013).
+///
+/// Returns the hit distance and the barycentric weights of `p0`, `p1` and `p2`.
+/// Rays through a shared edge or vertex hit at least one of the adjacent
+/// triangles, so closed meshes have no cracks.
+pub fn intersect_triangle(
+    ray: &Ray,
+    p0: &Vec3,
+    p1: &Vec3,
+    p2: &Vec3,
+    t_min: f32,
+    t_max: f32,
+) -> Option<(f32, [f32; 3])> {
+    let dir = ray.dir;
+    let abs_dir = Vec3::of(dir.x.abs(), dir.y.abs(), dir.z.abs());
+    let kz = if abs_dir.x > abs_dir.y {
+        if abs_dir.x > abs_dir.z { 0 } else { 2 }
+    } else if abs_dir.y > abs_dir.z {
+        1
+    } else {
+        2
+    };
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    if dir[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+    if dir[kz] == 0.0 {
+        return None;
+    }
+    let sx = dir[kx] / dir[kz];
+    let sy = dir[ky] / dir[kz];
+    let sz = 1.0 / dir[kz];
+
+    let a = p0 - ray.base;
+    let b = p1 - ray.base;
+    let c = p2 - ray.base;
+    let ax = a[kx] - sx * a[kz];
+    let ay = a[ky] - sy * a[kz];
+    let bx = b[kx] - sx * b[kz];
+    let by = b[ky] - sy * b[kz];
+    let cx = c[kx] - sx * c[kz];
+    let cy = c[ky] - sy * c[kz];
+
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+    // Fall back to double precision on edges, where f32 can't decide the side.
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+        v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+        w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+    }
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+
+    let az = sz * a[kz];
+    let bz = sz * b[kz];
+    let cz = sz * c[kz];
+    let t = (u * az + v * bz + w * cz) / det;
+    if t < t_min || t_max < t {
+        return None;
+    }
+    Some((t, [u / det, v / det, w / det]))
+}
+
+pub fn triangle_area(p0: &Vec3, p1: &Vec3, p2: &Vec3) -> f32 {
+    0.5 * (p1 - *p0).cross(&(p2 - *p0)).length()
+}
+
+/// Uniformly distributed point in the triangle.
+pub fn sample_triangle(p0: &Vec3, p1: &Vec3, p2: &Vec3, rng: &mut impl MyRng) -> Vec3 {
+    let su = rng.random_f32().sqrt();
+    let b1 = su * (1.0 - rng.random_f32());
+    let b2 = su - b1;
+    (1.0 - su) * p0 + b1 * p1 + b2 * p2
+}
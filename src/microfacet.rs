@@ -0,0 +1,333 @@
+//! Rough surfaces as many tiny mirrors, oriented by the GGX (Trowbridge-Reitz)
+//! distribution. Vectors are in a local frame with the surface normal along
+//! z, `wo` points back along the incoming ray and `wi` is the scattered
+//! direction.
+
+use std::f32::consts::PI;
+
+use crate::{geo::Vec3, random::MyRng, render::Color};
+
+/// GGX distribution of microfacet normals, stretched by `alpha_x` along the
+/// local x axis and by `alpha_y` along y.
+#[derive(Debug, Clone, Copy)]
+pub struct Ggx {
+    alpha_x: f32,
+    alpha_y: f32,
+}
+
+/// A pair of directions evaluated against a microfacet lobe.
+pub struct Evaluation {
+    /// Normal of the microfacets that scatter `wo` into `wi`.
+    pub m: Vec3,
+    /// BSDF times the cosine of `wi`, without the Fresnel factor.
+    pub value: f32,
+    /// Density per solid angle of finding `wi` through
+    /// `sample_visible_normal`, without the Fresnel factor.
+    pub pdf: f32,
+}
+
+impl Ggx {
+    /// `roughness` in [0, 1] is the square root of GGX's alpha, which makes
+    /// it perceptually linear. `None` for surfaces too smooth to sample
+    /// reliably, those are better treated as perfectly specular.
+    pub fn from_roughness(roughness: f32) -> Option<Self> {
+        let alpha = roughness * roughness;
+        (alpha >= 1.0e-3).then_some(Self {
+            alpha_x: alpha,
+            alpha_y: alpha,
+        })
+    }
+
+    /// Highlights stretched along the local x axis by `anisotropy` in [0, 1],
+    /// mapped as in the Disney BRDF. Never perfectly specular, the alphas
+    /// are at least 1e-3.
+    pub fn anisotropic(roughness: f32, anisotropy: f32) -> Self {
+        let alpha = roughness * roughness;
+        let aspect = (1.0 - 0.9 * anisotropy).sqrt();
+        Self {
+            alpha_x: (alpha / aspect).max(1.0e-3),
+            alpha_y: (alpha * aspect).max(1.0e-3),
+        }
+    }
+
+    /// Density of microfacet normals, per projected solid angle.
+    pub fn d(&self, m: &Vec3) -> f32 {
+        if m.z <= 0.0 {
+            return 0.0;
+        }
+        let (ax, ay) = (self.alpha_x, self.alpha_y);
+        let denominator = (m.x / ax).powi(2) + (m.y / ay).powi(2) + m.z * m.z;
+        1.0 / (PI * ax * ay * denominator * denominator)
+    }
+
+    /// Smith's auxiliary function, how much of the surface rises above a
+    /// ray in direction `w`.
+    fn lambda(&self, w: &Vec3) -> f32 {
+        let cos2 = w.z * w.z;
+        if cos2 == 0.0 {
+            return f32::INFINITY;
+        }
+        // alpha^2 tan^2, with alpha interpolated for the azimuth of `w`.
+        let alpha2_tan2 = ((self.alpha_x * w.x).powi(2) + (self.alpha_y * w.y).powi(2)) / cos2;
+        0.5 * ((1.0 + alpha2_tan2).sqrt() - 1.0)
+    }
+
+    /// Fraction of the microfacets facing `w` that are visible from it.
+    pub fn g1(&self, w: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Fraction of microfacets visible from both directions, height
+    /// correlated.
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Microfacet normal sampled proportional to how much of it `wo` sees,
+    /// Heitz, "Sampling the GGX Distribution of Visible Normals" (JCGT 2018).
+    /// `wo` must be above the surface.
+    pub fn sample_visible_normal(&self, wo: &Vec3, rng: &mut impl MyRng) -> Vec3 {
+        // Stretch to the configuration of a hemisphere with alpha 1.
+        let v = Vec3::of(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z).unit();
+        let length2 = v.x * v.x + v.y * v.y;
+        let t1 = if length2 > 0.0 {
+            Vec3::of(-v.y, v.x, 0.0) / length2.sqrt()
+        } else {
+            Vec3::of(1.0, 0.0, 0.0)
+        };
+        let t2 = v.cross(&t1);
+
+        // Uniform point on the projected disk, squeezed onto the half seen
+        // from `v`.
+        let r = rng.random_f32().sqrt();
+        let phi = 2.0 * PI * rng.random_f32();
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + v.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let n = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * v;
+
+        Vec3::of(self.alpha_x * n.x, self.alpha_y * n.y, n.z.max(0.0)).unit()
+    }
+
+    /// Density of `sample_visible_normal` picking `m`.
+    pub fn visible_normal_pdf(&self, wo: &Vec3, m: &Vec3) -> f32 {
+        self.g1(wo) * wo.dot(m).max(0.0) * self.d(m) / wo.z
+    }
+
+    /// Reflection off the microfacets. `None` unless both directions are
+    /// above the surface.
+    pub fn reflection(&self, wo: &Vec3, wi: &Vec3) -> Option<Evaluation> {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return None;
+        }
+        let m = (wo + *wi).unit();
+        let d = self.d(&m);
+        // The Jacobian of reflecting is 1 / (4 wo.m).
+        Some(Evaluation {
+            m,
+            value: d * self.g(wo, wi) / (4.0 * wo.z),
+            pdf: self.g1(wo) * d / (4.0 * wo.z),
+        })
+    }
+
+    /// Refraction through the microfacets into a medium whose index of
+    /// refraction is `eta` times that on the side of `wo`. No radiance
+    /// scaling by `eta`, like the smooth dielectric. `None` unless `wi` is
+    /// below the surface and both directions see the same microfacet side.
+    pub fn refraction(&self, wo: &Vec3, wi: &Vec3, eta: f32) -> Option<Evaluation> {
+        if wo.z <= 0.0 || wi.z >= 0.0 {
+            return None;
+        }
+        // Generalized half vector, pointing above the surface.
+        let mut m = (wo + eta * wi).unit();
+        if m.z < 0.0 {
+            m = -m;
+        }
+        let (cos_o, cos_i) = (wo.dot(&m), wi.dot(&m));
+        if cos_o <= 0.0 || cos_i >= 0.0 {
+            return None;
+        }
+        let d = self.d(&m);
+        let denominator = (cos_o + eta * cos_i).powi(2);
+        // The Jacobian of refracting is eta^2 |wi.m| / (wo.m + eta wi.m)^2.
+        let jacobian = eta * eta * -cos_i / denominator;
+        Some(Evaluation {
+            m,
+            value: d * self.g(wo, wi) * cos_o * jacobian / wo.z,
+            pdf: self.g1(wo) * cos_o * d / wo.z * jacobian,
+        })
+    }
+}
+
+/// Fraction of unpolarized light reflected at a smooth dielectric boundary,
+/// 1 for total internal reflection. `refraction_ratio` is the index of
+/// refraction of the incident side over that of the far side.
+pub fn fresnel_reflectance(cos_incident: f32, refraction_ratio: f32) -> f32 {
+    let sin_transmitted_squared =
+        refraction_ratio * refraction_ratio * (1.0 - cos_incident * cos_incident);
+    if sin_transmitted_squared >= 1.0 {
+        return 1.0;
+    }
+    let cos_transmitted = (1.0 - sin_transmitted_squared).sqrt();
+    let r_perpendicular = (refraction_ratio * cos_incident - cos_transmitted)
+        / (refraction_ratio * cos_incident + cos_transmitted);
+    let r_parallel = (cos_incident - refraction_ratio * cos_transmitted)
+        / (cos_incident + refraction_ratio * cos_transmitted);
+    0.5 * (r_perpendicular * r_perpendicular + r_parallel * r_parallel)
+}
+
+/// Schlick's approximation of the Fresnel equations, blending from the
+/// reflectance `f0` at normal incidence to white at grazing angles.
+pub fn schlick(f0: Color, cos_incident: f32) -> Color {
+    let weight = (1.0 - cos_incident.clamp(0.0, 1.0)).powi(5);
+    f0 + weight * (Color::of(1.0, 1.0, 1.0) - f0)
+}
+
+/// Index of refraction of a conductor, whose imaginary part `k` describes
+/// how fast light dies out inside. Per colour channel.
+#[derive(Debug, Clone, Copy)]
+pub struct ComplexIor {
+    pub eta: Color,
+    pub k: Color,
+}
+
+impl ComplexIor {
+    /// Measured values at the red, green and blue wavelengths.
+    pub const GOLD: Self = Self::of([0.143, 0.374, 1.442], [3.983, 2.385, 1.603]);
+    pub const COPPER: Self = Self::of([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]);
+    pub const ALUMINIUM: Self = Self::of([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]);
+    pub const SILVER: Self = Self::of([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]);
+
+    const fn of(eta: [f32; 3], k: [f32; 3]) -> Self {
+        Self {
+            eta: Color {
+                x: eta[0],
+                y: eta[1],
+                z: eta[2],
+            },
+            k: Color {
+                x: k[0],
+                y: k[1],
+                z: k[2],
+            },
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "gold" => Some(Self::GOLD),
+            "copper" => Some(Self::COPPER),
+            "aluminium" | "aluminum" => Some(Self::ALUMINIUM),
+            "silver" => Some(Self::SILVER),
+            _ => None,
+        }
+    }
+
+    /// Fraction of unpolarized light reflected by the Fresnel equations for
+    /// conductors, seen from outside at `cos_incident` to the normal.
+    pub fn reflectance(&self, cos_incident: f32) -> Color {
+        let cos2 = cos_incident.clamp(0.0, 1.0).powi(2);
+        let sin2 = 1.0 - cos2;
+        let channel = |eta: f32, k: f32| {
+            let (eta2, k2) = (eta * eta, k * k);
+            let t0 = eta2 - k2 - sin2;
+            let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+            let t1 = a2_plus_b2 + cos2;
+            let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+            let t2 = 2.0 * cos2.sqrt() * a;
+            let r_perpendicular = (t1 - t2) / (t1 + t2);
+            let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+            let t4 = t2 * sin2;
+            let r_parallel = r_perpendicular * (t3 - t4) / (t3 + t4);
+            0.5 * (r_perpendicular + r_parallel)
+        };
+        Color::of(
+            channel(self.eta.x, self.k.x),
+            channel(self.eta.y, self.k.y),
+            channel(self.eta.z, self.k.z),
+        )
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::random::MySmallRng;
+
+    pub(crate) fn direction(cos_theta: f32, phi: f32) -> Vec3 {
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        Vec3::of(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+
+    /// Bins sampled directions over the sphere, uniform in the cosine and
+    /// the azimuth so that every bin has the same solid angle, and compares
+    /// the counts with `pdf` integrated over each bin. `sample` returns the
+    /// direction and the density it reports for it, `None` for absorbed
+    /// samples.
+    pub(crate) fn assert_pdf_matches_histogram(
+        mut sample: impl FnMut(&mut MySmallRng) -> Option<(Vec3, f32)>,
+        pdf: impl Fn(&Vec3) -> f32,
+    ) {
+        const COS_BINS: usize = 20;
+        const PHI_BINS: usize = 40;
+        const SUBDIVISIONS: usize = 8;
+        let n = 1 << 20;
+        let (cos_width, phi_width) = (2.0 / COS_BINS as f32, 2.0 * PI / PHI_BINS as f32);
+        let bin_of = |w: &Vec3| {
+            let cos = ((w.z + 1.0) / cos_width) as usize;
+            let phi = (w.y.atan2(w.x).rem_euclid(2.0 * PI) / phi_width) as usize;
+            cos.min(COS_BINS - 1) * PHI_BINS + phi.min(PHI_BINS - 1)
+        };
+
+        let mut rng = MySmallRng::from_seed(2);
+        let mut counts = vec![0; COS_BINS * PHI_BINS];
+        for _ in 0..n {
+            if let Some((w, sample_pdf)) = sample(&mut rng) {
+                let w = w.unit();
+                let expected = pdf(&w);
+                assert!(
+                    (sample_pdf - expected).abs() <= 1.0e-3 * expected,
+                    "sampled with density {sample_pdf}, pdf {expected}"
+                );
+                counts[bin_of(&w)] += 1;
+            }
+        }
+
+        for (bin, &count) in counts.iter().enumerate() {
+            let (cos_bin, phi_bin) = (bin / PHI_BINS, bin % PHI_BINS);
+            let mut integral = 0.0;
+            for i in 0..SUBDIVISIONS {
+                for j in 0..SUBDIVISIONS {
+                    let offset = |k: usize| (k as f32 + 0.5) / SUBDIVISIONS as f32;
+                    let cos = -1.0 + cos_width * (cos_bin as f32 + offset(i));
+                    let phi = phi_width * (phi_bin as f32 + offset(j));
+                    integral += pdf(&direction(cos, phi));
+                }
+            }
+            let expected =
+                n as f32 * integral * cos_width * phi_width / (SUBDIVISIONS * SUBDIVISIONS) as f32;
+            let tolerance = 5.0 * expected.sqrt() + 0.02 * expected + 5.0;
+            assert!(
+                (count as f32 - expected).abs() <= tolerance,
+                "bin {cos_bin}, {phi_bin}: {count} samples, {expected} expected"
+            );
+        }
+    }
+
+    #[test]
+    fn visible_normal_pdf_matches_histogram() {
+        let wo = direction(0.6, 0.3);
+        for ggx in [
+            Ggx::from_roughness(0.7).unwrap(),
+            Ggx::anisotropic(0.7, 0.8),
+        ] {
+            assert_pdf_matches_histogram(
+                |rng| {
+                    let m = ggx.sample_visible_normal(&wo, rng);
+                    Some((m, ggx.visible_normal_pdf(&wo, &m)))
+                },
+                |m| ggx.visible_normal_pdf(&wo, m),
+            );
+        }
+    }
+}
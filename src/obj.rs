@@ -0,0 +1,585 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{geo::Vec3, mesh::Mesh, render::Color, scene::Material};
+
+/// One mesh per group and material combination found in an OBJ file.
+pub struct ObjMesh {
+    pub group: String,
+    pub mesh: Arc<Mesh>,
+    /// `None` if the faces were not assigned a material with `usemtl`.
+    pub material: Option<Material>,
+}
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io {
+        path: PathBuf,
+        cause: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl Error for ObjError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ObjError::Io { cause, .. } => Some(cause),
+            ObjError::Parse { .. } => None,
+        }
+    }
+}
+
+impl Display for ObjError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjError::Io { path, cause } => write!(f, "{}: {cause}", path.display()),
+            ObjError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{line}: {message}", path.display()),
+        }
+    }
+}
+
+/// Loads a Wavefront OBJ file together with the MTL libraries it references.
+/// Polygons are triangulated as fans around their first vertex.
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<ObjMesh>, ObjError> {
+    let path = path.as_ref();
+    let source = read(path)?;
+    parse_obj(&source, path)
+}
+
+fn read(path: &Path) -> Result<String, ObjError> {
+    std::fs::read_to_string(path).map_err(|cause| ObjError::Io {
+        path: path.to_owned(),
+        cause,
+    })
+}
+
+/// A face corner: indices into the position, texcoord and normal lists.
+type Corner = (usize, Option<usize>, Option<usize>);
+
+#[derive(Default)]
+struct MeshBuilder {
+    positions: Vec<Vec3>,
+    uvs: Vec<[f32; 2]>,
+    normals: Vec<Vec3>,
+    has_uvs: bool,
+    has_normals: bool,
+    triangles: Vec<[u32; 3]>,
+    vertex_of_corner: HashMap<Corner, u32>,
+}
+
+impl MeshBuilder {
+    fn vertex(&mut self, corner: Corner, obj: &ObjData) -> u32 {
+        if let Some(index) = self.vertex_of_corner.get(&corner) {
+            return *index;
+        }
+        let index = self.positions.len() as u32;
+        self.positions.push(obj.positions[corner.0]);
+        self.uvs.push(corner.1.map_or([0.0, 0.0], |i| obj.uvs[i]));
+        self.normals
+            .push(corner.2.map_or(Vec3::zero(), |i| obj.normals[i]));
+        self.has_uvs &= corner.1.is_some();
+        self.has_normals &= corner.2.is_some();
+        self.vertex_of_corner.insert(corner, index);
+        index
+    }
+
+    fn build(self) -> Mesh {
+        let mut mesh = Mesh::new(self.positions, self.triangles);
+        if self.has_uvs {
+            mesh = mesh.with_uvs(self.uvs);
+        }
+        if self.has_normals {
+            mesh = mesh.with_normals(self.normals);
+        }
+        mesh
+    }
+}
+
+#[derive(Default)]
+struct ObjData {
+    positions: Vec<Vec3>,
+    uvs: Vec<[f32; 2]>,
+    normals: Vec<Vec3>,
+}
+
+fn parse_obj(source: &str, path: &Path) -> Result<Vec<ObjMesh>, ObjError> {
+    let mut data = ObjData::default();
+    let mut materials: HashMap<String, Material> = HashMap::new();
+    let mut builders: Vec<((String, Option<String>), MeshBuilder)> = vec![];
+    let mut builder_index: HashMap<(String, Option<String>), usize> = HashMap::new();
+    let mut group = String::from("default");
+    let mut material: Option<String> = None;
+
+    for (line_number, line) in source.lines().enumerate() {
+        let line_number = line_number + 1;
+        let err = |message: String| ObjError::Parse {
+            path: path.to_owned(),
+            line: line_number,
+            message,
+        };
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let args: Vec<&str> = tokens.collect();
+        match keyword {
+            "v" => {
+                // An optional weight, or a vertex colour from some exporters,
+                // both ignored.
+                if !matches!(args.len(), 3 | 4 | 6) {
+                    return Err(err(format!(
+                        "expected 3, 4 or 6 numbers, got {}",
+                        args.len()
+                    )));
+                }
+                data.positions.push(parse_vec3(&args, 3..=6).map_err(err)?)
+            }
+            "vn" => data.normals.push(parse_vec3(&args, 3..=3).map_err(err)?),
+            "vt" => {
+                let uv = parse_floats(&args, 1..=3).map_err(err)?;
+                data.uvs.push([uv[0], uv.get(1).copied().unwrap_or(0.0)]);
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(err(format!(
+                        "a face needs at least 3 vertices, got {}",
+                        args.len()
+                    )));
+                }
+                let corners = args
+                    .iter()
+                    .map(|arg| parse_corner(arg, &data))
+                    .collect::<Result<Vec<Corner>, String>>()
+                    .map_err(err)?;
+                let key = (group.clone(), material.clone());
+                let index = *builder_index.entry(key.clone()).or_insert_with(|| {
+                    builders.push((
+                        key,
+                        MeshBuilder {
+                            has_uvs: true,
+                            has_normals: true,
+                            ..Default::default()
+                        },
+                    ));
+                    builders.len() - 1
+                });
+                let builder = &mut builders[index].1;
+                let first = builder.vertex(corners[0], &data);
+                for pair in corners[1..].windows(2) {
+                    let b = builder.vertex(pair[0], &data);
+                    let c = builder.vertex(pair[1], &data);
+                    builder.triangles.push([first, b, c]);
+                }
+            }
+            "g" | "o" => {
+                group = if args.is_empty() {
+                    String::from("default")
+                } else {
+                    args.join(" ")
+                };
+            }
+            "usemtl" => {
+                let name = args.join(" ");
+                if !materials.contains_key(&name) {
+                    return Err(err(format!("unknown material '{name}'")));
+                }
+                material = Some(name);
+            }
+            "mtllib" => {
+                let dir = path.parent().unwrap_or(Path::new(""));
+                for file in args {
+                    let mtl_path = dir.join(file);
+                    materials.extend(parse_mtl(&read(&mtl_path)?, &mtl_path)?);
+                }
+            }
+            // Smoothing groups, lines, points, free-form geometry and the like
+            // don't contribute triangles.
+            _ => {}
+        }
+    }
+
+    Ok(builders
+        .into_iter()
+        .filter(|(_, builder)| !builder.triangles.is_empty())
+        .map(|((group, material), builder)| ObjMesh {
+            group,
+            mesh: Arc::new(builder.build()),
+            material: material.map(|name| materials[&name].clone()),
+        })
+        .collect())
+}
+
+fn parse_floats(args: &[&str], count: std::ops::RangeInclusive<usize>) -> Result<Vec<f32>, String> {
+    if !count.contains(&args.len()) {
+        return Err(format!(
+            "expected {} to {} numbers, got {}",
+            count.start(),
+            count.end(),
+            args.len()
+        ));
+    }
+    args.iter()
+        .map(|a| {
+            a.parse::<f32>()
+                .map_err(|_| format!("'{a}' is not a number"))
+        })
+        .collect()
+}
+
+fn parse_vec3(args: &[&str], count: std::ops::RangeInclusive<usize>) -> Result<Vec3, String> {
+    let v = parse_floats(args, count)?;
+    Ok(Vec3::of(v[0], v[1], v[2]))
+}
+
+/// Resolves a 1-based, possibly negative (relative) OBJ index into `0..len`.
+fn resolve_index(token: &str, len: usize, what: &str) -> Result<usize, String> {
+    let index: i64 = token
+        .parse()
+        .map_err(|_| format!("'{token}' is not a valid {what} index"))?;
+    let resolved = match index {
+        i if i > 0 => i - 1,
+        i if i < 0 => len as i64 + i,
+        _ => return Err(format!("{what} index 0 is not allowed")),
+    };
+    if resolved < 0 || resolved >= len as i64 {
+        return Err(format!(
+            "{what} index {index} is out of range, {len} defined so far"
+        ));
+    }
+    Ok(resolved as usize)
+}
+
+fn parse_corner(token: &str, data: &ObjData) -> Result<Corner, String> {
+    let mut parts = token.split('/');
+    let position = resolve_index(parts.next().unwrap_or(""), data.positions.len(), "vertex")?;
+    let uv = match parts.next() {
+        None | Some("") => None,
+        Some(t) => Some(resolve_index(t, data.uvs.len(), "texture coordinate")?),
+    };
+    let normal = match parts.next() {
+        None | Some("") => None,
+        Some(t) => Some(resolve_index(t, data.normals.len(), "normal")?),
+    };
+    if parts.next().is_some() {
+        return Err(format!("malformed face vertex '{token}'"));
+    }
+    Ok((position, uv, normal))
+}
+
+#[derive(Clone, Copy)]
+struct MtlParameters {
+    diffuse: Color,
+    specular: Color,
+    specular_exponent: f32,
+    dissolve: f32,
+    index_of_refraction: f32,
+}
+
+impl MtlParameters {
+    /// Transparent materials become glass, materials with a dominant specular
+    /// colour become metal and everything else is diffuse.
+    fn to_material(self) -> Material {
+        if self.dissolve < 1.0 {
+            return Material::Dieletric {
+                index_of_refraction: self.index_of_refraction,
+                absorption: Color::zero(),
+                roughness: 0.0,
+            };
+        }
+        let luminance = |c: &Color| 0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z;
+        if luminance(&self.specular) > luminance(&self.diffuse) {
+            // Phong exponents map to roughness like a Beckmann distribution would.
+            let fuzz = (2.0 / (self.specular_exponent + 2.0)).sqrt().min(1.0);
+            Material::Metal {
+                albedo: self.specular.into(),
+                fuzz,
+            }
+        } else {
+            Material::Lambertian {
+                albedo: self.diffuse.into(),
+            }
+        }
+    }
+}
+
+impl Default for MtlParameters {
+    fn default() -> Self {
+        Self {
+            diffuse: Color::of(0.8, 0.8, 0.8),
+            specular: Color::zero(),
+            specular_exponent: 0.0,
+            dissolve: 1.0,
+            index_of_refraction: 1.5,
+        }
+    }
+}
+
+fn parse_mtl(source: &str, path: &Path) -> Result<HashMap<String, Material>, ObjError> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlParameters)> = None;
+
+    for (line_number, line) in source.lines().enumerate() {
+        let err = |message: String| ObjError::Parse {
+            path: path.to_owned(),
+            line: line_number + 1,
+            message,
+        };
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let args: Vec<&str> = tokens.collect();
+        if keyword == "newmtl" {
+            if let Some((name, parameters)) = current.take() {
+                materials.insert(name, parameters.to_material());
+            }
+            current = Some((args.join(" "), MtlParameters::default()));
+            continue;
+        }
+        let Some((_, parameters)) = current.as_mut() else {
+            return Err(err(format!("'{keyword}' before the first newmtl")));
+        };
+        let scalar = || parse_floats(&args, 1..=1).map(|v| v[0]);
+        match keyword {
+            "Kd" => parameters.diffuse = parse_vec3(&args, 3..=3).map_err(err)?,
+            "Ks" => parameters.specular = parse_vec3(&args, 3..=3).map_err(err)?,
+            "Ns" => parameters.specular_exponent = scalar().map_err(err)?,
+            "d" => parameters.dissolve = scalar().map_err(err)?,
+            "Tr" => parameters.dissolve = 1.0 - scalar().map_err(err)?,
+            "Ni" => {
+                let ior = scalar().map_err(err)?;
+                if ior <= 0.0 {
+                    return Err(err(format!(
+                        "index of refraction must be positive, got {ior}"
+                    )));
+                }
+                parameters.index_of_refraction = ior;
+            }
+            // Everything else (ambient, emission, texture maps, illumination
+            // model, ...) has no equivalent in our materials.
+            _ => {}
+        }
+    }
+    if let Some((name, parameters)) = current {
+        materials.insert(name, parameters.to_material());
+    }
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::Texture;
+
+    fn parse(source: &str) -> Vec<ObjMesh> {
+        parse_obj(source, Path::new("test.obj")).unwrap()
+    }
+
+    fn error(source: &str) -> String {
+        match parse_obj(source, Path::new("test.obj")) {
+            Ok(_) => panic!("parsed:\n{source}"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn xyz(vectors: &[Vec3]) -> Vec<(f32, f32, f32)> {
+        vectors.iter().map(|v| (v.x, v.y, v.z)).collect()
+    }
+
+    /// Four corners of a unit square and a fifth point, for polygons.
+    const POSITIONS: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0.5 1.5 0\n";
+
+    #[test]
+    fn triangulates_polygons_as_fans() {
+        let meshes = parse(&format!("{POSITIONS}f 1 2 3 4\nf 1 2 3 5 4\n"));
+        assert_eq!(meshes.len(), 1);
+        let mesh = &meshes[0].mesh;
+        assert_eq!(mesh.positions().len(), 5);
+        assert_eq!(
+            mesh.triangles(),
+            [[0, 1, 2], [0, 2, 3], [0, 1, 2], [0, 2, 4], [0, 4, 3]]
+        );
+        assert_eq!(xyz(&mesh.positions()[4..]), [(0.5, 1.5, 0.0)]);
+    }
+
+    #[test]
+    fn accepts_weights_and_vertex_colours() {
+        let meshes = parse("v 0 0 0 1\nv 1 0 0 0.5 0.5 0.5\nv 1 1 0\nf 1 2 3\n");
+        assert_eq!(xyz(&meshes[0].mesh.positions()[1..2]), [(1.0, 0.0, 0.0)]);
+    }
+
+    #[test]
+    fn resolves_relative_indices() {
+        let meshes = parse(&format!("{POSITIONS}f -3 -2 -1\n"));
+        let mesh = &meshes[0].mesh;
+        assert_eq!(
+            xyz(mesh.positions()),
+            [(1.0, 1.0, 0.0), (0.0, 1.0, 0.0), (0.5, 1.5, 0.0)]
+        );
+    }
+
+    #[test]
+    fn reads_texture_coordinates_and_normals() {
+        let attributes = "vt 0 0\nvt 1 0\nvt 1 1\nvn 0 0 1\n";
+        let meshes = parse(&format!("{POSITIONS}{attributes}f 1/1/1 2/2/1 3/3/1\n"));
+        let mesh = &meshes[0].mesh;
+        assert_eq!(mesh.uvs().unwrap(), [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0]]);
+        assert_eq!(xyz(mesh.normals().unwrap()), [(0.0, 0.0, 1.0); 3]);
+
+        let meshes = parse(&format!("{POSITIONS}{attributes}f 1//1 2//1 3//1\n"));
+        assert!(meshes[0].mesh.uvs().is_none());
+        assert!(meshes[0].mesh.normals().is_some());
+
+        // A single corner without normals drops them for the whole mesh.
+        let meshes = parse(&format!(
+            "{POSITIONS}{attributes}f 1//1 2//1 3//1\nf 1 3 4\n"
+        ));
+        assert!(meshes[0].mesh.normals().is_none());
+    }
+
+    #[test]
+    fn splits_by_group() {
+        let meshes = parse(&format!(
+            "{POSITIONS}f 1 2 3\ng left\nf 1 3 4\no right\nf 2 3 5\ng left\nf 3 4 5\n"
+        ));
+        let groups: Vec<(&str, usize)> = meshes
+            .iter()
+            .map(|m| (m.group.as_str(), m.mesh.triangles().len()))
+            .collect();
+        assert_eq!(groups, [("default", 1), ("left", 2), ("right", 1)]);
+    }
+
+    #[test]
+    fn splits_by_material() {
+        let directory = std::env::temp_dir().join(format!("obj-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("test.mtl"),
+            "newmtl red\nKd 1 0 0\nnewmtl blue\nKd 0 0 1\n",
+        )
+        .unwrap();
+        let source = format!(
+            "mtllib test.mtl\n{POSITIONS}f 1 2 3\nusemtl red\nf 1 3 4\nusemtl blue\nf 2 3 5\n\
+             usemtl red\nf 3 4 5\n"
+        );
+        let meshes = parse_obj(&source, &directory.join("test.obj"));
+        std::fs::remove_dir_all(&directory).unwrap();
+        let diffuse = |mesh: &ObjMesh| match &mesh.material {
+            None => None,
+            Some(Material::Lambertian {
+                albedo: Texture::Solid(c),
+            }) => Some((c.x, c.y, c.z)),
+            Some(other) => panic!("unexpected material {other:?}"),
+        };
+        let materials: Vec<_> = meshes
+            .unwrap()
+            .iter()
+            .map(|m| (diffuse(m), m.mesh.triangles().len()))
+            .collect();
+        assert_eq!(
+            materials,
+            [
+                (None, 1),
+                (Some((1.0, 0.0, 0.0)), 2),
+                (Some((0.0, 0.0, 1.0)), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_mtl_parameters_onto_materials() {
+        let materials = parse_mtl(
+            "newmtl plastic\nKd 0.2 0.4 0.6\nKs 0.1 0.1 0.1\n\
+             newmtl steel\nKd 0.1 0.1 0.1\nKs 0.7 0.7 0.7\nNs 198\n\
+             newmtl glass\nd 0.5\nNi 1.33\n\
+             newmtl window\nTr 0.9\n",
+            Path::new("test.mtl"),
+        )
+        .unwrap();
+        assert_eq!(materials.len(), 4);
+        match &materials["plastic"] {
+            Material::Lambertian {
+                albedo: Texture::Solid(c),
+            } => assert_eq!((c.x, c.y, c.z), (0.2, 0.4, 0.6)),
+            other => panic!("plastic became {other:?}"),
+        }
+        match &materials["steel"] {
+            Material::Metal {
+                albedo: Texture::Solid(c),
+                fuzz,
+            } => {
+                assert_eq!((c.x, c.y, c.z), (0.7, 0.7, 0.7));
+                assert!((fuzz - 0.1).abs() < 1.0e-6, "fuzz {fuzz}");
+            }
+            other => panic!("steel became {other:?}"),
+        }
+        for (name, ior) in [("glass", 1.33), ("window", 1.5)] {
+            match &materials[name] {
+                Material::Dieletric {
+                    index_of_refraction,
+                    ..
+                } => assert_eq!(*index_of_refraction, ior),
+                other => panic!("{name} became {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        assert_eq!(
+            error(&format!("{POSITIONS}f 1 2 0\n")),
+            "test.obj:6: vertex index 0 is not allowed"
+        );
+        assert_eq!(
+            error(&format!("{POSITIONS}f 1 2 6\n")),
+            "test.obj:6: vertex index 6 is out of range, 5 defined so far"
+        );
+        assert_eq!(
+            error(&format!("{POSITIONS}f 1 2 -6\n")),
+            "test.obj:6: vertex index -6 is out of range, 5 defined so far"
+        );
+        assert_eq!(
+            error(&format!("{POSITIONS}vt 0 0\nf 1/1 2/2 3/1\n")),
+            "test.obj:7: texture coordinate index 2 is out of range, 1 defined so far"
+        );
+        assert_eq!(
+            error("v 0 0\n"),
+            "test.obj:1: expected 3, 4 or 6 numbers, got 2"
+        );
+        assert_eq!(
+            error(&format!("{POSITIONS}usemtl missing\n")),
+            "test.obj:6: unknown material 'missing'"
+        );
+        assert_eq!(
+            error(&format!("{POSITIONS}f 1 2\n")),
+            "test.obj:6: a face needs at least 3 vertices, got 2"
+        );
+        let mtl_error = |source: &str| match parse_mtl(source, Path::new("test.mtl")) {
+            Ok(_) => panic!("parsed:\n{source}"),
+            Err(e) => e.to_string(),
+        };
+        assert_eq!(
+            mtl_error("newmtl glass\nd 0.5\nNi -1\n"),
+            "test.mtl:3: index of refraction must be positive, got -1"
+        );
+        assert_eq!(
+            mtl_error("Kd 1 1 1\n"),
+            "test.mtl:1: 'Kd' before the first newmtl"
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use rand::{rngs::SmallRng, Rng, SeedableRng};
+use rand::{Rng, SeedableRng, rngs::SmallRng};
 
 pub struct MySmallRng {
     rng: SmallRng,
@@ -20,10 +20,22 @@ impl MyRng for MySmallRng {
     }
 }
 
+impl Default for MySmallRng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MySmallRng {
     pub fn new() -> Self {
         Self {
             rng: SmallRng::from_os_rng(),
         }
     }
+
+    pub fn from_seed(seed: u64) -> Self {
+        Self {
+            rng: SmallRng::seed_from_u64(seed),
+        }
+    }
 }
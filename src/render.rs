@@ -2,30 +2,89 @@ use std::{
     error::Error,
     fmt::Display,
     io::Write,
+    path::Path,
     process::{Command, Stdio},
     thread::{self, ScopedJoinHandle},
 };
 
 use crate::{
+    exr::{self, ExrCompression},
     geo::Vec3,
+    image_io::{self, ImageError, ImageFormat},
     random::{MyRng, MySmallRng},
-    scene::{ray_color, Scene},
+    scene::{Scene, integrator::Integrator},
 };
 
+#[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub base: Vec3,
     pub dir: Vec3,
+    pub cone: Cone,
+    /// When the ray travels, for moving objects. The camera picks it within
+    /// the shutter interval, all rays of a path share it.
+    pub time: f32,
+}
+
+/// The area a ray stands for, as a cone around it. Textures are filtered
+/// over its width where the ray hits them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cone {
+    /// Width at the base of the ray.
+    pub width: f32,
+    /// Growth of the width per unit of length along the ray.
+    pub spread: f32,
+}
+
+impl Cone {
+    pub fn width_at(&self, distance: f32) -> f32 {
+        self.width + self.spread * distance
+    }
 }
 
 impl Ray {
+    /// A ray without a cone, sharp as a line, at time 0.
+    pub fn new(base: Vec3, dir: Vec3) -> Self {
+        Self {
+            base,
+            dir,
+            cone: Cone::default(),
+            time: 0.0,
+        }
+    }
+
+    pub fn with_time(mut self, time: f32) -> Self {
+        self.time = time;
+        self
+    }
+
     pub fn at(&self, t: f32) -> Vec3 {
         self.base + t * self.dir
     }
+
+    /// A ray leaving from `base`, a point on this ray, towards `dir`, at the
+    /// same time. Its cone continues this ray's, ignoring how curved
+    /// surfaces focus or spread it.
+    pub fn spawn(&self, base: Vec3, dir: Vec3) -> Self {
+        let distance = (base - self.base).length();
+        Self {
+            base,
+            dir,
+            cone: Cone {
+                width: self.cone.width_at(distance),
+                spread: self.cone.spread,
+            },
+            time: self.time,
+        }
+    }
 }
 
 pub struct Camera {
     origin: Vec3,
     viewport: Viewport,
+    lens_radius: f32,
+    focus_distance: f32,
+    shutter_open: f32,
+    shutter_close: f32,
 }
 
 struct Viewport {
@@ -34,58 +93,174 @@ struct Viewport {
     v1: Vec3,
 }
 
+impl Default for Camera {
+    /// Looks down -z from the origin through a 4x2 viewport at distance 1.
+    fn default() -> Self {
+        Self::new(
+            Vec3::zero(),
+            Vec3::of(0.0, 0.0, -1.0),
+            Vec3::of(0.0, 1.0, 0.0),
+            90.0,
+            2.0,
+        )
+    }
+}
+
 impl Camera {
-    pub fn default() -> Self {
+    /// `vertical_fov` is given in degrees, `aspect_ratio` is width / height.
+    ///
+    /// # Panics
+    ///
+    /// If `look_from` and `look_at` are the same point or `up` is parallel
+    /// to the line between them, which leaves the orientation open.
+    pub fn new(
+        look_from: Vec3,
+        look_at: Vec3,
+        up: Vec3,
+        vertical_fov: f32,
+        aspect_ratio: f32,
+    ) -> Self {
         Self {
-            origin: Vec3::zero(),
-            viewport: Viewport::default(),
+            origin: look_from,
+            viewport: Viewport::new(look_from, look_at, up, vertical_fov, aspect_ratio),
+            lens_radius: 0.0,
+            focus_distance: 1.0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
         }
     }
 
-    fn get_ray(&self, u: f32, v: f32) -> Ray {
-        let base = self.origin;
-        let dir = self.viewport.base + u * self.viewport.v0 + v * self.viewport.v1 - base;
+    /// Turns the pinhole into a thin lens of diameter `aperture` that is in
+    /// focus at `focus_distance` from the camera. An aperture of 0 keeps the
+    /// pinhole.
+    pub fn with_defocus(mut self, aperture: f32, focus_distance: f32) -> Self {
+        self.lens_radius = aperture / 2.0;
+        self.focus_distance = focus_distance;
+        self
+    }
+
+    /// Rays get times spread uniformly from `open` to `close`, which blurs
+    /// moving objects. Equal times take a still image.
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.shutter_open = open;
+        self.shutter_close = close;
+        self
+    }
+
+    /// `pixel_spread` is the angle a pixel covers, in radians.
+    fn get_ray(&self, u: f32, v: f32, pixel_spread: f32, rng: &mut impl MyRng) -> Ray {
+        let origin = self.origin;
+        let target = self.viewport.base + u * self.viewport.v0 + v * self.viewport.v1;
+        let cone = Cone {
+            width: 0.0,
+            spread: pixel_spread,
+        };
+        let time = if self.shutter_close > self.shutter_open {
+            rng.random_f32_from_range(self.shutter_open, self.shutter_close)
+        } else {
+            self.shutter_open
+        };
+        if self.lens_radius == 0.0 {
+            return Ray {
+                base: origin,
+                dir: target - origin,
+                cone,
+                time,
+            };
+        }
 
-        Ray { base, dir }
+        let focus_point = origin + self.focus_distance * (target - origin);
+        let lens = self.lens_radius * Vec3::random_in_unit_disk(rng);
+        let base = origin + lens.x * self.viewport.v0.unit() - lens.y * self.viewport.v1.unit();
+        Ray {
+            base,
+            dir: focus_point - base,
+            cone,
+            time,
+        }
     }
 }
 
 impl Viewport {
-    pub fn default() -> Self {
+    /// The viewport sits at distance 1 in front of `look_from`. `base` is its
+    /// top left corner, `v0` spans it left to right and `v1` top to bottom.
+    fn new(look_from: Vec3, look_at: Vec3, up: Vec3, vertical_fov: f32, aspect_ratio: f32) -> Self {
+        let height = 2.0 * (vertical_fov.to_radians() / 2.0).tan();
+        let width = aspect_ratio * height;
+
+        let w = (look_from - look_at).unit();
+        let u = up.cross(&w);
+        assert!(
+            u.length() > 0.0,
+            "the view direction must be nonzero and not parallel to up"
+        );
+        let u = u.unit();
+        let v = w.cross(&u);
+
+        let v0 = width * u;
+        let v1 = -height * v;
         Self {
-            base: Vec3::of(-2.0, 1.0, -1.0),
-            v0: Vec3::of(4.0, 0.0, 0.0),
-            v1: Vec3::of(0.0, -2.0, 0.0),
+            base: look_from - w - 0.5 * v0 - 0.5 * v1,
+            v0,
+            v1,
         }
     }
 }
 
-pub fn render(camera: &Camera, scene: &Scene, img: &mut Image) {
-    const N_THREADS: usize = 4;
+pub struct RenderSettings {
+    pub n_threads: usize,
+    /// Seeds the per thread random number generators. Renders with the same
+    /// seed and thread count are reproducible, `None` seeds from the OS.
+    pub seed: Option<u64>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            n_threads: 4,
+            seed: None,
+        }
+    }
+}
+
+pub fn render(
+    camera: &Camera,
+    scene: &Scene,
+    integrator: &impl Integrator,
+    img: &mut Image,
+    settings: &RenderSettings,
+) {
+    let n_threads = settings.n_threads;
+    let seed = settings.seed;
+    // The viewport is at distance 1, so its pixel height is the angle.
+    let pixel_spread = camera.viewport.v1.length() / img.height as f32;
 
     let process_row = move |col_skip: usize, img: &mut Image| {
-        let mut rng = MySmallRng::new();
+        let mut rng = match seed {
+            Some(seed) => MySmallRng::from_seed(seed.wrapping_add(col_skip as u64)),
+            None => MySmallRng::new(),
+        };
 
-        for y in (col_skip..img.height).step_by(N_THREADS) {
+        for y in (col_skip..img.height).step_by(n_threads) {
             for x in 0..img.width {
                 let mut color = Vec3::zero();
 
                 for _ in 0..img.samples_per_pixel {
                     let u = (x as f32 + rng.random_f32()) / img.width as f32;
                     let v = (y as f32 + rng.random_f32()) / img.height as f32;
-                    let ray = camera.get_ray(u, v);
-                    color += ray_color(&ray, scene, 0, &mut rng);
+                    let ray = camera.get_ray(u, v, pixel_spread, &mut rng);
+                    color += integrator.li(&ray, scene, &mut rng);
                 }
                 *img.get_mut(x, y).unwrap() = color;
             }
         }
     };
-    if N_THREADS != 1 {
+    if n_threads != 1 {
         thread::scope(|s| {
             let mut threads: Vec<ScopedJoinHandle<Image>> = vec![];
-            for i in 0..N_THREADS {
+            for i in 0..n_threads {
                 let j = i;
-                let f = process_row.clone();
+                let f = process_row;
                 let mut img = img.clone();
                 threads.push(s.spawn(move || -> Image {
                     f(j, &mut img);
@@ -106,22 +281,19 @@ pub fn render(camera: &Camera, scene: &Scene, img: &mut Image) {
 pub type Color = Vec3;
 
 impl Color {
-    fn ppm_string(&self, samples_per_pixel: i32) -> String {
+    /// Averages the accumulated samples, applies gamma 2 and clamps to 8 bit.
+    fn to_rgb8(self, samples_per_pixel: i32) -> [u8; 3] {
         let scale = 1.0f32 / samples_per_pixel as f32;
-        let scale_and_clamp = |x: f32| {
-            let y = (x * scale).sqrt();
-            if y < 0.0 {
-                0.0
-            } else if y > 1.0 {
-                1.0
-            } else {
-                y
-            }
-        };
+        let scale_and_clamp = |x: f32| (x * scale).sqrt().clamp(0.0, 1.0);
 
         let r = (scale_and_clamp(self.x) * 255.99999).floor() as u8;
         let g = (scale_and_clamp(self.y) * 255.99999).floor() as u8;
         let b = (scale_and_clamp(self.z) * 255.99999).floor() as u8;
+        [r, g, b]
+    }
+
+    fn ppm_string(&self, samples_per_pixel: i32) -> String {
+        let [r, g, b] = self.to_rgb8(samples_per_pixel);
         format!("{r} {g} {b}")
     }
 }
@@ -139,14 +311,14 @@ impl Image {
         if x > self.width || y > self.height {
             return None;
         }
-        return self.data.get_mut(y * self.width + x);
+        self.data.get_mut(y * self.width + x)
     }
 
     fn get(&self, x: usize, y: usize) -> Option<&Color> {
         if x > self.width || y > self.height {
             return None;
         }
-        return self.data.get(y * self.width + x);
+        self.data.get(y * self.width + x)
     }
 
     fn add(&mut self, rhs: &Image) {
@@ -156,28 +328,66 @@ impl Image {
         }
     }
 
-    pub fn empty(width: usize, height: usize) -> Self {
+    pub fn empty(width: usize, height: usize, samples_per_pixel: i32) -> Self {
         Self {
             width,
             height,
             data: vec![Color::of(0.0, 0.0, 0.0,); width * height],
-            samples_per_pixel: 100,
+            samples_per_pixel,
+        }
+    }
+
+    /// Writes the image to `path`, the encoder is picked from the extension.
+    /// `.ppm` and `.png` are gamma corrected 8 bit, `.hdr`, `.pfm` and `.exr`
+    /// store the linear per pixel average. `.exr` files are compressed with
+    /// `exr_compression`.
+    pub fn save(
+        &self,
+        path: impl AsRef<Path>,
+        exr_compression: ExrCompression,
+    ) -> Result<(), ImageError> {
+        let path = path.as_ref();
+        let format = ImageFormat::from_path(path)?;
+        let (w, h) = (self.width, self.height);
+        let mut out = image_io::create(path)?;
+        match format {
+            ImageFormat::Ppm => image_io::write_ppm(&mut out, w, h, &self.to_rgb8())?,
+            ImageFormat::Png => image_io::write_png(&mut out, w, h, &self.to_rgb8())?,
+            ImageFormat::Hdr => image_io::write_hdr(&mut out, w, h, &self.averaged())?,
+            ImageFormat::Pfm => image_io::write_pfm(&mut out, w, h, &self.averaged())?,
+            ImageFormat::Exr => exr::write_exr(&mut out, w, h, &self.averaged(), exr_compression)?,
         }
+        out.flush()?;
+        Ok(())
+    }
+
+    fn to_rgb8(&self) -> Vec<u8> {
+        self.data
+            .iter()
+            .flat_map(|c| c.to_rgb8(self.samples_per_pixel))
+            .collect()
+    }
+
+    /// Linear radiance per pixel, without gamma or clamping.
+    pub fn averaged(&self) -> Vec<Color> {
+        let scale = 1.0 / self.samples_per_pixel as f32;
+        self.data.iter().map(|c| scale * c).collect()
     }
 
+    /// Pipes the image to ImageMagick's `display` for a quick look.
     pub fn write_to_display_process(&self) -> Result<(), SubprocessError> {
         let mut cmd = Command::new("display").stdin(Stdio::piped()).spawn()?;
         {
             let mut stdin = cmd.stdin.take().expect("stdin is configured");
 
             let line = format!("P3\n{w}\n{h}\n255\n", w = self.width, h = self.height);
-            stdin.write(line.as_bytes())?;
+            stdin.write_all(line.as_bytes())?;
 
             for y in 0..self.height {
                 for x in 0..self.width {
                     let c = self.get(x, y).unwrap();
                     let line = format!("{}\n", c.ppm_string(self.samples_per_pixel));
-                    stdin.write(line.as_bytes())?;
+                    stdin.write_all(line.as_bytes())?;
                 }
             }
         }
@@ -201,12 +411,12 @@ pub struct SubprocessError {
 
 impl Error for SubprocessError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        None
+        Some(self.cause.as_ref())
     }
 }
 
 impl Display for SubprocessError {
-    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        todo!()
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "subprocess failed: {}", self.cause)
     }
 }
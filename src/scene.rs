@@ -1,59 +1,955 @@
+use std::{
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
 use crate::{
-    geo::Vec3,
+    bvh::{Aabb, Bvh},
+    environment::EnvironmentMap,
+    geo::{Frame, Keyframes, Transform, Vec3},
+    medium::Medium,
+    mesh::{Mesh, intersect_triangle, sample_triangle, triangle_area},
+    microfacet::{ComplexIor, Ggx, fresnel_reflectance},
+    obj::{self, ObjError},
+    principled::{Principled, PrincipledBsdf},
     random::MyRng,
-    render::{Color, Ray},
+    render::{Color, Cone, Ray},
+    texture::Texture,
 };
 
+pub mod file;
+pub mod integrator;
+
 pub struct Scene {
     objects: Vec<Object>,
+    /// Objects without bounds, infinite planes. They stay out of the BVH and
+    /// are tested one by one.
+    unbounded: Vec<Object>,
+    /// Indexed after `objects` in the BVH.
+    instances: Vec<Instance>,
+    /// Built on first use, reset whenever an object is added.
+    bvh: OnceLock<Bvh>,
+    /// Indices of the emitting objects.
+    lights: Vec<usize>,
+    background: Background,
+    /// Fills the space outside of objects, up to the surfaces rays hit. Rays
+    /// that leave the scene see the background unattenuated.
+    medium: Option<Medium>,
+}
+
+/// Radiance arriving along rays that leave the scene.
+#[derive(Debug, Clone)]
+pub enum Background {
+    /// White at the horizon blending into light blue straight up.
+    Sky,
+    Solid(Color),
+    /// A panorama, sampled as a light.
+    Map(Arc<EnvironmentMap>),
+}
+
+impl Background {
+    fn color(&self, dir: &Vec3) -> Color {
+        match self {
+            Background::Sky => {
+                let unit = dir.unit();
+                assert!(0.9999 <= unit.length() && unit.length() <= 1.00001);
+                let t = 0.5 * (unit.y + 1.0);
+                assert!((0.0..=1.0000).contains(&t));
+                (1.0 - t) * Color::of(1.0, 1.0, 1.0) + t * Color::of(0.5, 0.7, 1.0)
+            }
+            Background::Solid(color) => *color,
+            Background::Map(map) => map.radiance(dir),
+        }
+    }
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Scene {
     pub fn new() -> Self {
-        Self { objects: vec![] }
+        Self {
+            objects: vec![],
+            unbounded: vec![],
+            instances: vec![],
+            bvh: OnceLock::new(),
+            lights: vec![],
+            background: Background::Sky,
+            medium: None,
+        }
+    }
+
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+    }
+
+    /// Haze for the whole scene. The camera must not be inside an object
+    /// that bounds another medium.
+    pub fn set_medium(&mut self, medium: Medium) {
+        self.medium = Some(medium);
     }
 
     pub fn add_sphere(&mut self, center: Vec3, radius: f32, material: Material) {
-        self.objects.push(Object {
+        self.add_object(Object {
             shape: Shape::Sphere { center, radius },
             material,
+            inside: None,
+            light: false,
+            material_id: 0,
+        });
+    }
+
+    /// Sphere whose center moves at constant speed from `center0` at
+    /// `time0` to `center1` at `time1`, resting before and after.
+    pub fn add_moving_sphere(
+        &mut self,
+        (center0, time0): (Vec3, f32),
+        (center1, time1): (Vec3, f32),
+        radius: f32,
+        material: Material,
+    ) {
+        self.add_object(Object {
+            shape: Shape::MovingSphere {
+                motion: LinearMotion {
+                    from: center0,
+                    to: center1,
+                    start_time: time0,
+                    end_time: time1,
+                },
+                radius,
+            },
+            material,
+            inside: None,
+            light: false,
+            material_id: 0,
+        });
+    }
+
+    pub fn add_triangle(&mut self, v0: Vec3, v1: Vec3, v2: Vec3, material: Material) {
+        self.add_object(Object {
+            shape: Shape::Triangle { v0, v1, v2 },
+            material,
+            inside: None,
+            light: false,
+            material_id: 0,
+        });
+    }
+
+    /// Infinite plane through `point`, facing `normal`.
+    pub fn add_plane(&mut self, point: Vec3, normal: Vec3, material: Material) {
+        self.add_object(Object {
+            shape: Shape::Plane {
+                point,
+                normal: normal.unit(),
+            },
+            material,
+            inside: None,
+            light: false,
+            material_id: 0,
+        });
+    }
+
+    /// Parallelogram spanned by the edges `u` and `v` from `corner`. Faces
+    /// the side `u` x `v` points to.
+    pub fn add_quad(&mut self, corner: Vec3, u: Vec3, v: Vec3, material: Material) {
+        self.add_object(Object {
+            shape: Shape::Quad { corner, u, v },
+            material,
+            inside: None,
+            light: false,
+            material_id: 0,
+        });
+    }
+
+    pub fn add_disk(&mut self, center: Vec3, normal: Vec3, radius: f32, material: Material) {
+        self.add_object(Object {
+            shape: Shape::Disk {
+                center,
+                normal: normal.unit(),
+                radius,
+            },
+            material,
+            inside: None,
+            light: false,
+            material_id: 0,
+        });
+    }
+
+    /// Axis-aligned box between two opposite corners, facing outwards.
+    pub fn add_box(&mut self, a: Vec3, b: Vec3, material: Material) {
+        let bounds = Aabb::of(a, b);
+        self.add_object(Object {
+            shape: Shape::Box {
+                min: bounds.min,
+                max: bounds.max,
+            },
+            material,
+            inside: None,
+            light: false,
+            material_id: 0,
+        });
+    }
+
+    pub fn add_mesh(&mut self, mesh: Arc<Mesh>, material: Material) {
+        self.add_object(Object {
+            shape: Shape::Mesh { mesh },
+            material,
+            inside: None,
+            light: false,
+            material_id: 0,
+        });
+    }
+
+    /// Places a copy of `scene` that shares its geometry. Lights inside
+    /// instances are not sampled directly, only found by scattering, and the
+    /// instanced scene's background is ignored.
+    ///
+    /// # Panics
+    ///
+    /// If `scene` contains infinite planes, which can't be bounded.
+    pub fn add_instance(&mut self, scene: Arc<Scene>, transform: Transform) {
+        assert!(
+            scene.unbounded.is_empty(),
+            "instanced scenes can't contain planes"
+        );
+        let local = scene.bounds();
+        if local.min.x > local.max.x {
+            return;
+        }
+        self.instances.push(Instance {
+            scene,
+            transform,
+            keyframes: None,
+            bounds: transformed_bounds(&local, &transform),
+        });
+        self.bvh = OnceLock::new();
+    }
+
+    /// Like `add_instance`, with a transform that changes over time.
+    ///
+    /// # Panics
+    ///
+    /// If `scene` contains infinite planes.
+    pub fn add_animated_instance(&mut self, scene: Arc<Scene>, keyframes: Keyframes) {
+        assert!(
+            scene.unbounded.is_empty(),
+            "instanced scenes can't contain planes"
+        );
+        let local = scene.bounds();
+        if local.min.x > local.max.x {
+            return;
+        }
+        self.instances.push(Instance {
+            scene,
+            transform: Transform::identity(),
+            bounds: swept_bounds(&local, &keyframes),
+            keyframes: Some(keyframes),
         });
+        self.bvh = OnceLock::new();
+    }
+
+    /// Adds the objects of `boundary` filled with `medium`. They should be
+    /// closed and must not overlap other volumes, media don't nest. Give
+    /// them `Material::Interface` for a volume without a visible surface.
+    pub fn add_volume(&mut self, boundary: Scene, medium: Medium) {
+        for object in boundary.objects.into_iter().chain(boundary.unbounded) {
+            self.add_object(Object {
+                inside: Some(medium.clone()),
+                ..object
+            });
+        }
+        // Instances keep the media of their own objects.
+        self.instances.extend(boundary.instances);
+        self.bvh = OnceLock::new();
+    }
+
+    /// Adds all meshes of an OBJ file. Faces without a material from the MTL
+    /// library use `default_material`.
+    pub fn add_obj(
+        &mut self,
+        path: impl AsRef<Path>,
+        default_material: Material,
+    ) -> Result<(), ObjError> {
+        for obj_mesh in obj::load(path)? {
+            let material = obj_mesh
+                .material
+                .unwrap_or_else(|| default_material.clone());
+            self.add_mesh(obj_mesh.mesh, material);
+        }
+        Ok(())
+    }
+
+    fn add_object(&mut self, mut object: Object) {
+        object.material_id = self.objects.len() + self.unbounded.len();
+        if !object.shape.is_bounded() {
+            self.unbounded.push(object);
+            return;
+        }
+        // Lights without area, like empty meshes, can't be sampled.
+        object.light =
+            matches!(object.material, Material::DiffuseLight { .. }) && object.shape.area() > 0.0;
+        if object.light {
+            self.lights.push(self.objects.len());
+        }
+        self.objects.push(object);
+        self.bvh = OnceLock::new();
+    }
+
+    fn bvh(&self) -> &Bvh {
+        self.bvh.get_or_init(|| {
+            let bounds: Vec<Aabb> = self
+                .objects
+                .iter()
+                .map(|o| o.shape.bounds())
+                .chain(self.instances.iter().map(|i| i.bounds))
+                .collect();
+            Bvh::build(&bounds)
+        })
+    }
+
+    /// Bounds of everything but the infinite planes.
+    fn bounds(&self) -> Aabb {
+        let objects = self.objects.iter().map(|o| o.shape.bounds());
+        let instances = self.instances.iter().map(|i| i.bounds);
+        objects
+            .chain(instances)
+            .fold(Aabb::empty(), |bounds, b| bounds.union(&b))
+    }
+
+    fn closest_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.closest_hit_object(ray, t_min, t_max)
+            .map(|(_, hit_record)| hit_record)
     }
 
-    fn closest_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let mut closest_hit: Option<HitRecord> = None;
-        let mut closest_t_abs = f32::MAX;
-        for object in self.objects.iter() {
-            let Some(hit_record) = object.hit(ray, t_min, t_max) else {
-                continue;
+    /// Like `closest_hit`, also returns the object that was hit, inside
+    /// instances the instanced one.
+    fn closest_hit_object(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(&Object, HitRecord<'_>)> {
+        let mut closest = self.bvh().closest_hit(ray, t_min, t_max, |i, t_max| {
+            let hit = match self.objects.get(i) {
+                Some(object) => object
+                    .hit(ray, t_min, t_max)
+                    .map(|hit_record| (object, hit_record)),
+                None => self.instances[i - self.objects.len()].hit(ray, t_min, t_max),
             };
-            let t_abs = hit_record.t.abs();
-            if t_abs < closest_t_abs {
-                closest_t_abs = t_abs;
-                closest_hit = Some(hit_record);
+            hit.map(|(object, hit_record)| (hit_record.t, (object, hit_record)))
+        });
+        for object in &self.unbounded {
+            let t_max = closest
+                .as_ref()
+                .map_or(t_max, |(_, hit_record)| hit_record.t);
+            if let Some(hit_record) = object.hit(ray, t_min, t_max) {
+                closest = Some((object, hit_record));
             }
         }
-        closest_hit
+        closest
+    }
+
+    /// Lights `sample_light` picks from, the environment map counting as
+    /// one.
+    fn light_count(&self) -> usize {
+        self.lights.len() + matches!(self.background, Background::Map(_)) as usize
     }
+
+    /// Picks a light uniformly and a point uniformly on its surface at
+    /// `time`, as seen from `p`, or a direction on the environment map by
+    /// its brightness. `None` if there are no lights or the point faces
+    /// away.
+    fn sample_light(&self, p: &Vec3, time: f32, rng: &mut impl MyRng) -> Option<LightSample> {
+        let count = self.light_count();
+        if count == 0 {
+            return None;
+        }
+        let index = ((rng.random_f32() * count as f32) as usize).min(count - 1);
+        if index == self.lights.len() {
+            let Background::Map(map) = &self.background else {
+                unreachable!("only environment maps count past the lights")
+            };
+            let (dir, pdf) = map.sample(rng)?;
+            return Some(LightSample {
+                dir,
+                distance: f32::INFINITY,
+                emitted: map.radiance(&dir),
+                pdf: pdf / count as f32,
+            });
+        }
+        let light = &self.objects[self.lights[index]];
+        let (point, normal) = light.shape.sample_point(time, rng);
+        let to_point = point - p;
+        let distance_squared = to_point.length_squared();
+        let distance = distance_squared.sqrt();
+        let dir = to_point / distance;
+        let cos_light = -dir.dot(&normal);
+        if cos_light <= 0.0 {
+            return None;
+        }
+        let pdf_area = 1.0 / (count as f32 * light.shape.area());
+        Some(LightSample {
+            dir,
+            distance,
+            emitted: light.material.emitted(&FACE::FRONT),
+            pdf: pdf_area * distance_squared / cos_light,
+        })
+    }
+
+    /// Whether `sample_light` can pick `object`. Infinite planes and objects
+    /// inside instances are never sampled.
+    fn is_light(&self, object: &Object) -> bool {
+        // Objects of instanced scenes live in those scenes.
+        object.light
+            && self
+                .objects
+                .as_ptr_range()
+                .contains(&std::ptr::from_ref(object))
+    }
+
+    /// Probability density per solid angle that `sample_light` picks the
+    /// point `hit_record` on `light` as seen from `p`.
+    fn light_pdf(&self, light: &Object, p: &Vec3, hit_record: &HitRecord) -> f32 {
+        if !self.is_light(light) {
+            return 0.0;
+        }
+        let dir = hit_record.p - p;
+        let distance_squared = dir.length_squared();
+        let cos_light = dir.dot(&hit_record.geometric_normal).abs() / distance_squared.sqrt();
+        distance_squared / (cos_light * self.light_count() as f32 * light.shape.area())
+    }
+
+    /// Probability density per solid angle that `sample_light` picks the
+    /// direction `dir` on the environment map, zero for other backgrounds.
+    fn background_pdf(&self, dir: &Vec3) -> f32 {
+        match &self.background {
+            Background::Map(map) => map.pdf(&dir.unit()) / self.light_count() as f32,
+            Background::Sky | Background::Solid(_) => 0.0,
+        }
+    }
+
+    /// Fraction of light that gets from `p` a `distance` along the unit
+    /// vector `dir` at `time`, starting in `medium`. Only media and
+    /// interfaces let light through, any other surface blocks it. Light from
+    /// infinitely far away isn't dimmed by the medium the ray leaves in.
+    fn transmittance<'a>(
+        &'a self,
+        p: &Vec3,
+        dir: &Vec3,
+        distance: f32,
+        time: f32,
+        mut medium: Option<&'a Medium>,
+        rng: &mut impl MyRng,
+    ) -> Color {
+        let mut ray = Ray::new(*p, *dir).with_time(time);
+        // Same offset at both ends, so neither end point shadows itself.
+        let mut remaining = distance - 0.001;
+        let mut transmittance = Color::of(1.0, 1.0, 1.0);
+        loop {
+            let hit = self.closest_hit_object(&ray, 0.001, remaining);
+            if let Some(medium) = medium
+                && (hit.is_some() || remaining.is_finite())
+            {
+                let t = hit
+                    .as_ref()
+                    .map_or(remaining, |(_, hit_record)| hit_record.t);
+                transmittance = transmittance * medium.transmittance(&ray, t, rng);
+            }
+            let Some((object, hit_record)) = hit else {
+                return transmittance;
+            };
+            if !matches!(object.material, Material::Interface) {
+                return Color::zero();
+            }
+            medium = self.medium_behind(object, &hit_record, dir, medium);
+            remaining -= hit_record.t;
+            ray = Ray::new(hit_record.p, *dir).with_time(time);
+        }
+    }
+
+    /// The medium a ray leaving `hit_record` on `object` towards `dir`
+    /// travels through, given the medium it arrived in. Rays that go through
+    /// the surface enter the object's medium or leave it for the scene's.
+    /// Surfaces that transmit light are taken to be the boundaries of
+    /// closed objects, empty unless they bound a medium.
+    fn medium_behind<'a>(
+        &'a self,
+        object: &'a Object,
+        hit_record: &HitRecord,
+        dir: &Vec3,
+        medium: Option<&'a Medium>,
+    ) -> Option<&'a Medium> {
+        if dir.dot(&hit_record.geometric_normal) >= 0.0 {
+            return medium;
+        }
+        match hit_record.face {
+            FACE::FRONT => object.inside.as_ref(),
+            FACE::BACK => self.medium.as_ref(),
+        }
+    }
+}
+
+/// The unit direction towards a point on a light, the distance to it and
+/// the probability density of having picked that direction, per solid
+/// angle. Environment maps are infinitely far away.
+struct LightSample {
+    dir: Vec3,
+    distance: f32,
+    emitted: Color,
+    pdf: f32,
 }
 
 struct Object {
     shape: Shape,
     material: Material,
+    /// Medium filling the object.
+    inside: Option<Medium>,
+    /// Whether the scene the object was added to samples it as a light.
+    light: bool,
+    /// Tells the materials of a scene's objects apart in debug views, in
+    /// the order they were added.
+    material_id: usize,
+}
+
+/// A point moving at constant speed, resting before `start_time` and after
+/// `end_time`.
+#[derive(Clone, Copy)]
+struct LinearMotion {
+    from: Vec3,
+    to: Vec3,
+    start_time: f32,
+    end_time: f32,
+}
+
+impl LinearMotion {
+    fn at(&self, time: f32) -> Vec3 {
+        if self.end_time <= self.start_time {
+            return if time < self.start_time {
+                self.from
+            } else {
+                self.to
+            };
+        }
+        let f = ((time - self.start_time) / (self.end_time - self.start_time)).clamp(0.0, 1.0);
+        (1.0 - f) * self.from + f * self.to
+    }
+}
+
+/// A shared scene placed with a transform.
+struct Instance {
+    scene: Arc<Scene>,
+    /// From the instanced scene to this one.
+    transform: Transform,
+    /// Replaces `transform` for animated instances.
+    keyframes: Option<Keyframes>,
+    /// Over all times for animated instances.
+    bounds: Aabb,
+}
+
+impl Instance {
+    /// Intersects the ray in the instanced scene's space. Its parameter `t`
+    /// stays the same, the direction is not normalized.
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(&Object, HitRecord<'_>)> {
+        let transform = match &self.keyframes {
+            Some(keyframes) => keyframes.at(ray.time),
+            None => self.transform,
+        };
+        let inverse = transform.inverse();
+        let local = Ray {
+            base: inverse.point(&ray.base),
+            dir: inverse.vector(&ray.dir),
+            cone: Cone {
+                width: ray.cone.width * inverse.scale_factor(),
+                spread: ray.cone.spread,
+            },
+            time: ray.time,
+        };
+        let (object, hit_record) = self.scene.closest_hit_object(&local, t_min, t_max)?;
+        Some((object, hit_record.transformed(&transform)))
+    }
+}
+
+/// Bounds of the box `local` after `transform`.
+fn transformed_bounds(local: &Aabb, transform: &Transform) -> Aabb {
+    let mut bounds = Aabb::empty();
+    for corner in 0..8 {
+        let pick = |axis: usize| {
+            if corner & (1 << axis) == 0 {
+                local.min[axis]
+            } else {
+                local.max[axis]
+            }
+        };
+        bounds.grow(transform.point(&Vec3::of(pick(0), pick(1), pick(2))));
+    }
+    bounds
+}
+
+/// Bounds of the box `local` over all times of `keyframes`, from the bounds
+/// at steps between the keys.
+fn swept_bounds(local: &Aabb, keyframes: &Keyframes) -> Aabb {
+    const STEPS: usize = 16;
+    let times: Vec<f32> = keyframes.times().collect();
+    let mut bounds = transformed_bounds(local, &keyframes.at(times[0]));
+    let mut padding: f32 = 0.0;
+    for pair in times.windows(2) {
+        for step in 1..=STEPS {
+            let time = pair[0] + (pair[1] - pair[0]) * step as f32 / STEPS as f32;
+            bounds = bounds.union(&transformed_bounds(local, &keyframes.at(time)));
+        }
+        // Between the steps, rotating corners bulge out of the bounds by at
+        // most the sagitta of the arc they travel along.
+        let (start, end) = (keyframes.pose_at(pair[0]), keyframes.pose_at(pair[1]));
+        let turn = end.rotate - start.rotate;
+        let angle = (turn.x.abs() + turn.y.abs() + turn.z.abs()).to_radians() / STEPS as f32;
+        let reach = |scale: Vec3| {
+            let corner = |axis: usize| local.min[axis].abs().max(local.max[axis].abs());
+            (Vec3::of(corner(0), corner(1), corner(2)) * scale).length()
+        };
+        let radius = reach(start.scale).max(reach(end.scale));
+        padding = padding.max(radius * (1.0 - (angle / 2.0).cos()));
+    }
+    let padding = Vec3::of(padding, padding, padding);
+    Aabb::of(bounds.min - padding, bounds.max + padding)
 }
 
 enum Shape {
-    Sphere { center: Vec3, radius: f32 },
+    Sphere {
+        center: Vec3,
+        radius: f32,
+    },
+    MovingSphere {
+        motion: LinearMotion,
+        radius: f32,
+    },
+    /// Single triangle, counter-clockwise winding faces the front.
+    Triangle {
+        v0: Vec3,
+        v1: Vec3,
+        v2: Vec3,
+    },
+    Mesh {
+        mesh: Arc<Mesh>,
+    },
+    /// Infinite, `normal` is a unit vector. Never a sampled light, it has no
+    /// finite area.
+    Plane {
+        point: Vec3,
+        normal: Vec3,
+    },
+    /// Parallelogram, `u` x `v` faces the front.
+    Quad {
+        corner: Vec3,
+        u: Vec3,
+        v: Vec3,
+    },
+    Disk {
+        center: Vec3,
+        normal: Vec3,
+        radius: f32,
+    },
+    /// Axis-aligned, normals point outwards.
+    Box {
+        min: Vec3,
+        max: Vec3,
+    },
+}
+
+impl Shape {
+    fn bounds(&self) -> Aabb {
+        match self {
+            Shape::Sphere { center, radius } => {
+                let r = Vec3::of(radius.abs(), radius.abs(), radius.abs());
+                Aabb::of(center - r, center + r)
+            }
+            // Both ends bound everything in between.
+            Shape::MovingSphere { motion, radius } => {
+                let r = Vec3::of(radius.abs(), radius.abs(), radius.abs());
+                Aabb::of(motion.from - r, motion.from + r)
+                    .union(&Aabb::of(motion.to - r, motion.to + r))
+            }
+            Shape::Triangle { v0, v1, v2 } => {
+                let mut bounds = Aabb::of(*v0, *v1);
+                bounds.grow(*v2);
+                bounds
+            }
+            Shape::Mesh { mesh } => mesh.bounds(),
+            Shape::Plane { .. } => unreachable!("planes are kept out of the BVH"),
+            Shape::Quad { corner, u, v } => {
+                let mut bounds = Aabb::of(*corner, *corner + *u + *v);
+                bounds.grow(*corner + *u);
+                bounds.grow(*corner + *v);
+                bounds
+            }
+            Shape::Disk {
+                center,
+                normal,
+                radius,
+            } => {
+                // The disk's extent along an axis shrinks with the normal's
+                // component along it.
+                let extent = |n: f32| radius * (1.0 - n * n).max(0.0).sqrt();
+                let r = Vec3::of(extent(normal.x), extent(normal.y), extent(normal.z));
+                Aabb::of(center - r, center + r)
+            }
+            Shape::Box { min, max } => Aabb::of(*min, *max),
+        }
+    }
+
+    fn is_bounded(&self) -> bool {
+        !matches!(self, Shape::Plane { .. })
+    }
+
+    fn area(&self) -> f32 {
+        match self {
+            Shape::Sphere { radius, .. } | Shape::MovingSphere { radius, .. } => {
+                4.0 * std::f32::consts::PI * radius * radius
+            }
+            Shape::Triangle { v0, v1, v2 } => triangle_area(v0, v1, v2),
+            Shape::Mesh { mesh } => mesh.area(),
+            Shape::Plane { .. } => f32::INFINITY,
+            Shape::Quad { u, v, .. } => u.cross(v).length(),
+            Shape::Disk { radius, .. } => std::f32::consts::PI * radius * radius,
+            Shape::Box { min, max } => {
+                let d = max - *min;
+                2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+            }
+        }
+    }
+
+    /// Uniformly distributed point on the surface at `time` and the normal
+    /// there, with the same orientation as the normal used for hits.
+    fn sample_point(&self, time: f32, rng: &mut impl MyRng) -> (Vec3, Vec3) {
+        match self {
+            Shape::Sphere { center, radius } => {
+                let normal = Vec3::random_on_unit_sphere(rng);
+                (center + radius.abs() * normal, radius.signum() * normal)
+            }
+            Shape::MovingSphere { motion, radius } => {
+                let normal = Vec3::random_on_unit_sphere(rng);
+                (
+                    motion.at(time) + radius.abs() * normal,
+                    radius.signum() * normal,
+                )
+            }
+            Shape::Triangle { v0, v1, v2 } => (
+                sample_triangle(v0, v1, v2, rng),
+                (v1 - *v0).cross(&(v2 - *v0)).unit(),
+            ),
+            Shape::Mesh { mesh } => mesh.sample_point(rng),
+            Shape::Plane { .. } => unreachable!("planes are never sampled lights"),
+            Shape::Quad { corner, u, v } => (
+                *corner + rng.random_f32() * u + rng.random_f32() * v,
+                u.cross(v).unit(),
+            ),
+            Shape::Disk {
+                center,
+                normal,
+                radius,
+            } => {
+                let frame = Frame::from_z(normal);
+                let d = radius * Vec3::random_in_unit_disk(rng);
+                (center + frame.to_world(&d), *normal)
+            }
+            Shape::Box { min, max } => {
+                // Pick a face by its area, then a point on it.
+                let d = max - *min;
+                let areas = [d.y * d.z, d.z * d.x, d.x * d.y];
+                let mut pick = rng.random_f32() * (areas[0] + areas[1] + areas[2]);
+                let mut axis = 2;
+                for (i, area) in areas.iter().enumerate() {
+                    if pick < *area {
+                        axis = i;
+                        break;
+                    }
+                    pick -= area;
+                }
+                let corner = *min + Vec3::random(rng) * d;
+                let mut p = [corner.x, corner.y, corner.z];
+                let mut normal = [0.0; 3];
+                if rng.random_f32() < 0.5 {
+                    p[axis] = min[axis];
+                    normal[axis] = -1.0;
+                } else {
+                    p[axis] = max[axis];
+                    normal[axis] = 1.0;
+                }
+                (
+                    Vec3::of(p[0], p[1], p[2]),
+                    Vec3::of(normal[0], normal[1], normal[2]),
+                )
+            }
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Material {
-    Lambertian { albedo: Color },
-    Metal { albedo: Color, fuzz: f32 },
-    Dieletric { index_of_refraction: f32 },
+    Lambertian {
+        albedo: Texture,
+    },
+    /// Blurs the reflection by moving it up to `fuzz` in a random direction.
+    Metal {
+        albedo: Texture,
+        fuzz: f32,
+    },
+    /// Metal made of GGX microfacets that reflect by the Fresnel equations
+    /// for conductors. A `roughness` of 0 is a perfect mirror.
+    Conductor {
+        ior: ComplexIor,
+        roughness: f32,
+    },
+    /// Reflects or refracts by the Fresnel equations. A `roughness` above 0
+    /// scatters through GGX microfacets, like frosted glass.
+    Dieletric {
+        index_of_refraction: f32,
+        /// Beer-Lambert attenuation per unit of distance travelled inside,
+        /// zero for clear glass. Assumes closed objects that don't overlap.
+        absorption: Color,
+        roughness: f32,
+    },
+    /// Blend of plastic, metal and glass with an optional clear coat, driven
+    /// by textures. See `principled`.
+    Principled(Box<Principled>),
+    /// Emits `emit` from the front face and absorbs all incoming light.
+    DiffuseLight {
+        emit: Color,
+    },
+    /// Invisible, rays pass straight through. Bounds media.
+    Interface,
 }
 
 impl Material {
+    fn emitted(&self, face: &FACE) -> Color {
+        match (self, face) {
+            (Self::DiffuseLight { emit }, FACE::FRONT) => *emit,
+            _ => Color::zero(),
+        }
+    }
+
+    /// Whether the material scatters into a few directions only. Light
+    /// sampling can't find those, they are left to `scatter`.
+    fn is_specular(&self) -> bool {
+        match self {
+            Self::Lambertian { .. } => false,
+            Self::Metal { fuzz, .. } => *fuzz == 0.0,
+            Self::Conductor { roughness, .. } | Self::Dieletric { roughness, .. } => {
+                Ggx::from_roughness(*roughness).is_none()
+            }
+            Self::Principled(_) => false,
+            Self::DiffuseLight { .. } | Self::Interface => true,
+        }
+    }
+
+    /// The BSDF times the cosine to the normal for light leaving towards
+    /// `dir`, zero for specular materials.
+    fn evaluate(&self, ray_in: &Ray, hit_record: &HitRecord, dir: &Vec3) -> Color {
+        match self {
+            // `scatter` samples exactly proportional to the BSDF times the
+            // cosine, with `albedo` as the weight.
+            Self::Lambertian { albedo } | Self::Metal { albedo, .. } => {
+                self.pdf(ray_in, hit_record, dir) * hit_record.color_of(albedo)
+            }
+            Self::Conductor { ior, roughness } => {
+                let Some(ggx) = Ggx::from_roughness(*roughness) else {
+                    return Color::zero();
+                };
+                let (frame, wo) = hit_record.shading_frame(ray_in);
+                match ggx.reflection(&wo, &frame.to_local(&dir.unit())) {
+                    Some(reflection) => reflection.value * ior.reflectance(wo.dot(&reflection.m)),
+                    None => Color::zero(),
+                }
+            }
+            Self::Dieletric {
+                index_of_refraction,
+                absorption,
+                roughness,
+            } => {
+                let Some(ggx) = Ggx::from_roughness(*roughness) else {
+                    return Color::zero();
+                };
+                let (frame, wo) = hit_record.shading_frame(ray_in);
+                let eta = relative_ior(*index_of_refraction, &hit_record.face);
+                match rough_dielectric(&ggx, eta, &wo, &frame.to_local(&dir.unit())) {
+                    Some((value, _)) => value * transmittance(absorption, ray_in, hit_record),
+                    None => Color::zero(),
+                }
+            }
+            Self::Principled(principled) => {
+                let (frame, wo) = hit_record.shading_frame(ray_in);
+                hit_record
+                    .bsdf_of(principled)
+                    .evaluate(&wo, &frame.to_local(&dir.unit()))
+            }
+            Self::DiffuseLight { .. } | Self::Interface => Color::zero(),
+        }
+    }
+
+    /// Probability density per solid angle that `scatter` picks `dir`, zero
+    /// for specular materials.
+    fn pdf(&self, ray_in: &Ray, hit_record: &HitRecord, dir: &Vec3) -> f32 {
+        let dir = dir.unit();
+        let cos_theta = hit_record.normal.dot(&dir);
+        if self.is_specular() {
+            return 0.0;
+        }
+        match self {
+            Self::Lambertian { .. } => cos_theta.max(0.0) / std::f32::consts::PI,
+            Self::Metal { fuzz, .. } => {
+                if cos_theta <= 0.0 {
+                    return 0.0;
+                }
+                // The scattered direction points at a uniformly distributed
+                // point of the ball of radius `fuzz` around the reflection.
+                // Integrate the ball's density along the ray towards `dir`.
+                let reflected = ray_in.dir.unit().reflect(&hit_record.normal);
+                let along = dir.dot(&reflected);
+                let discriminant = fuzz * fuzz - dir.cross(&reflected).length_squared();
+                if discriminant <= 0.0 {
+                    return 0.0;
+                }
+                let t_far = along + discriminant.sqrt();
+                let t_near = (along - discriminant.sqrt()).max(0.0);
+                if t_far <= 0.0 {
+                    return 0.0;
+                }
+                let volume = 4.0 / 3.0 * std::f32::consts::PI * fuzz * fuzz * fuzz;
+                (t_far - t_near) * (t_far * t_far + t_far * t_near + t_near * t_near)
+                    / (3.0 * volume)
+            }
+            Self::Conductor { roughness, .. } => {
+                let Some(ggx) = Ggx::from_roughness(*roughness) else {
+                    return 0.0;
+                };
+                let (frame, wo) = hit_record.shading_frame(ray_in);
+                ggx.reflection(&wo, &frame.to_local(&dir))
+                    .map_or(0.0, |reflection| reflection.pdf)
+            }
+            Self::Dieletric {
+                index_of_refraction,
+                roughness,
+                ..
+            } => {
+                let Some(ggx) = Ggx::from_roughness(*roughness) else {
+                    return 0.0;
+                };
+                let (frame, wo) = hit_record.shading_frame(ray_in);
+                let eta = relative_ior(*index_of_refraction, &hit_record.face);
+                rough_dielectric(&ggx, eta, &wo, &frame.to_local(&dir)).map_or(0.0, |(_, pdf)| pdf)
+            }
+            Self::Principled(principled) => {
+                let (frame, wo) = hit_record.shading_frame(ray_in);
+                hit_record
+                    .bsdf_of(principled)
+                    .pdf(&wo, &frame.to_local(&dir))
+            }
+            Self::DiffuseLight { .. } | Self::Interface => 0.0,
+        }
+    }
+
     fn scatter(
         &self,
         ray_in: &Ray,
@@ -66,156 +962,672 @@ impl Material {
                 if scatter_direction.near_zero() {
                     scatter_direction = hit_record.normal;
                 }
-                let ray_out = Ray {
-                    base: hit_record.p,
-                    dir: scatter_direction,
-                };
+                let ray_out = ray_in.spawn(hit_record.p, scatter_direction);
                 Some(ScatterResult {
+                    pdf: Some(self.pdf(ray_in, hit_record, &scatter_direction)),
                     ray_out,
-                    attenuation: *albedo,
+                    attenuation: hit_record.color_of(albedo),
                 })
             }
             Self::Metal { albedo, fuzz } => {
                 let reflected = ray_in.dir.unit().reflect(&hit_record.normal);
-                if reflected.dot(&hit_record.normal) > 0.0 {
-                    let dir = reflected + fuzz * Vec3::random_in_unit_sphere(rng);
-                    let ray_out = Ray {
-                        base: hit_record.p,
-                        dir,
-                    };
+                let dir = reflected + fuzz * Vec3::random_in_unit_sphere(rng);
+                // Fuzzed directions below the surface are absorbed.
+                if dir.dot(&hit_record.normal) > 0.0 {
+                    let ray_out = ray_in.spawn(hit_record.p, dir);
                     Some(ScatterResult {
+                        pdf: (!self.is_specular()).then(|| self.pdf(ray_in, hit_record, &dir)),
                         ray_out,
-                        attenuation: *albedo,
+                        attenuation: hit_record.color_of(albedo),
                     })
                 } else {
                     None
                 }
             }
+            Self::Conductor { ior, roughness } => {
+                let (frame, wo) = hit_record.shading_frame(ray_in);
+                let Some(ggx) = Ggx::from_roughness(*roughness) else {
+                    let dir = ray_in.dir.unit().reflect(&hit_record.normal);
+                    return Some(ScatterResult {
+                        ray_out: ray_in.spawn(hit_record.p, dir),
+                        attenuation: ior.reflectance(wo.z),
+                        pdf: None,
+                    });
+                };
+                if wo.z <= 0.0 {
+                    return None;
+                }
+                let m = ggx.sample_visible_normal(&wo, rng);
+                let wi = (-wo).reflect(&m);
+                // Reflections off microfacets may point into the surface.
+                let reflection = ggx.reflection(&wo, &wi)?;
+                Some(ScatterResult {
+                    ray_out: ray_in.spawn(hit_record.p, frame.to_world(&wi)),
+                    attenuation: ggx.g(&wo, &wi) / ggx.g1(&wo) * ior.reflectance(wo.dot(&m)),
+                    pdf: Some(reflection.pdf),
+                })
+            }
             Self::Dieletric {
                 index_of_refraction,
+                absorption,
+                roughness,
             } => {
-                let refraction_ratio = match hit_record.face {
-                    FACE::FRONT => 1.0 / index_of_refraction,
-                    FACE::BACK => *index_of_refraction,
-                };
-                let unit_direction = ray_in.dir.unit();
-                let cos_theta = (-unit_direction).dot(&hit_record.normal).min(1.0);
-                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
-                let dir = if refraction_ratio * sin_theta > 1.0 {
-                    unit_direction.reflect(&hit_record.normal)
-                } else {
-                    unit_direction.refract(&hit_record.normal, refraction_ratio)
-                };
-
-                let ray_out = Ray {
-                    base: hit_record.p,
-                    dir,
+                let eta = relative_ior(*index_of_refraction, &hit_record.face);
+                let (dir, weight, pdf) = match Ggx::from_roughness(*roughness) {
+                    None => {
+                        let unit_direction = ray_in.dir.unit();
+                        let cos_theta = (-unit_direction).dot(&hit_record.normal).min(1.0);
+                        let dir = if fresnel_reflectance(cos_theta, 1.0 / eta) > rng.random_f32() {
+                            unit_direction.reflect(&hit_record.normal)
+                        } else {
+                            unit_direction.refract(&hit_record.normal, 1.0 / eta)
+                        };
+                        (dir, 1.0, None)
+                    }
+                    Some(ggx) => {
+                        let (frame, wo) = hit_record.shading_frame(ray_in);
+                        if wo.z <= 0.0 {
+                            return None;
+                        }
+                        let m = ggx.sample_visible_normal(&wo, rng);
+                        let reflect = fresnel_reflectance(wo.dot(&m), 1.0 / eta) > rng.random_f32();
+                        let wi = if reflect {
+                            (-wo).reflect(&m)
+                        } else {
+                            (-wo).refract(&m, 1.0 / eta)
+                        };
+                        // The microfacet may send the ray to the wrong side.
+                        if reflect != (wi.z > 0.0) {
+                            return None;
+                        }
+                        let (_, pdf) = rough_dielectric(&ggx, eta, &wo, &wi)?;
+                        (
+                            frame.to_world(&wi),
+                            ggx.g(&wo, &wi) / ggx.g1(&wo),
+                            Some(pdf),
+                        )
+                    }
                 };
                 Some(ScatterResult {
-                    ray_out,
-                    attenuation: Color::of(1.0, 1.0, 1.0),
+                    ray_out: ray_in.spawn(hit_record.p, dir),
+                    attenuation: weight * transmittance(absorption, ray_in, hit_record),
+                    pdf,
+                })
+            }
+            Self::Principled(principled) => {
+                let (frame, wo) = hit_record.shading_frame(ray_in);
+                let bsdf = hit_record.bsdf_of(principled);
+                let wi = bsdf.sample(&wo, rng)?;
+                let pdf = bsdf.pdf(&wo, &wi);
+                if pdf <= 0.0 {
+                    return None;
+                }
+                Some(ScatterResult {
+                    ray_out: ray_in.spawn(hit_record.p, frame.to_world(&wi)),
+                    attenuation: bsdf.evaluate(&wo, &wi) / pdf,
+                    pdf: Some(pdf),
                 })
             }
+            Self::DiffuseLight { .. } => None,
+            Self::Interface => Some(ScatterResult {
+                ray_out: ray_in.spawn(hit_record.p, ray_in.dir),
+                attenuation: Color::of(1.0, 1.0, 1.0),
+                pdf: None,
+            }),
+        }
+    }
+}
+
+/// Index of refraction on the far side of the surface over that on the side
+/// the ray comes from.
+fn relative_ior(index_of_refraction: f32, face: &FACE) -> f32 {
+    match face {
+        FACE::FRONT => index_of_refraction,
+        FACE::BACK => 1.0 / index_of_refraction,
+    }
+}
+
+/// Beer-Lambert transmittance of the segment that ends at `hit_record`. Only
+/// hits on a back face end a segment through the inside.
+fn transmittance(absorption: &Color, ray_in: &Ray, hit_record: &HitRecord) -> Color {
+    match hit_record.face {
+        FACE::FRONT => Color::of(1.0, 1.0, 1.0),
+        FACE::BACK => {
+            let distance = hit_record.t * ray_in.dir.length();
+            let transmittance = |a: f32| (-a * distance).exp();
+            Color::of(
+                transmittance(absorption.x),
+                transmittance(absorption.y),
+                transmittance(absorption.z),
+            )
         }
     }
 }
 
+/// BSDF times cosine and sampling density of rough glass, for local
+/// directions. `eta` is the index of refraction below the surface over that
+/// above.
+fn rough_dielectric(ggx: &Ggx, eta: f32, wo: &Vec3, wi: &Vec3) -> Option<(f32, f32)> {
+    let (evaluation, probability) = if wi.z > 0.0 {
+        let reflection = ggx.reflection(wo, wi)?;
+        let reflectance = fresnel_reflectance(wo.dot(&reflection.m), 1.0 / eta);
+        (reflection, reflectance)
+    } else {
+        let refraction = ggx.refraction(wo, wi, eta)?;
+        let reflectance = fresnel_reflectance(wo.dot(&refraction.m), 1.0 / eta);
+        (refraction, 1.0 - reflectance)
+    };
+    Some((probability * evaluation.value, probability * evaluation.pdf))
+}
+
 struct ScatterResult {
     ray_out: Ray,
     attenuation: Color,
+    /// Probability density per solid angle of `ray_out`, `None` for specular
+    /// scattering.
+    pdf: Option<f32>,
+}
+
+impl Object {
+    fn hit_sphere(
+        &self,
+        ray: &Ray,
+        center: &Vec3,
+        radius: f32,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<HitRecord<'_>> {
+        let oc = ray.base - center;
+        let a = ray.dir.length_squared();
+        let half_b = oc.dot(&ray.dir);
+        let c = oc.length_squared() - radius * radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant <= 0.0 {
+            return None;
+        }
+        let sqrtd = discriminant.sqrt();
+        let mut root = (-half_b - sqrtd) / a;
+        if root < t_min || t_max < root {
+            root = (-half_b + sqrtd) / a;
+            if root < t_min || t_max < root {
+                return None;
+            }
+        }
+        let p = ray.at(root);
+        let hit_record = HitRecord::new(
+            p,
+            root,
+            (p - center) / radius,
+            sphere_uv(&((p - center) / radius.abs())),
+            ray.dir,
+            &self.material,
+        );
+        // `v` runs along half a great circle, `u` along circles that
+        // shrink towards the poles. Going by `v` blurs less.
+        let uv_per_length = 1.0 / (std::f32::consts::PI * radius.abs());
+        // `u` grows around the y axis.
+        let tangent = Vec3::of(0.0, 1.0, 0.0).cross(&(p - center));
+        Some(
+            hit_record
+                .with_footprint(ray, uv_per_length)
+                .with_tangent(tangent),
+        )
+    }
 }
 
 impl Hittable for Object {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         match &self.shape {
-            Shape::Sphere { center, radius } => {
-                let oc = ray.base - center;
-                let a = ray.dir.length_squared();
-                let half_b = oc.dot(&ray.dir);
-                let c = oc.length_squared() - radius * radius;
-                let discriminant = half_b * half_b - a * c;
-                if discriminant <= 0.0 {
+            Shape::Sphere { center, radius } => self.hit_sphere(ray, center, *radius, t_min, t_max),
+            Shape::MovingSphere { motion, radius } => {
+                self.hit_sphere(ray, &motion.at(ray.time), *radius, t_min, t_max)
+            }
+            Shape::Triangle { v0, v1, v2 } => {
+                let (t, b) = intersect_triangle(ray, v0, v1, v2, t_min, t_max)?;
+                let cross = (v1 - *v0).cross(&(v2 - *v0));
+                let hit_record = HitRecord::new(
+                    b[0] * v0 + b[1] * v1 + b[2] * v2,
+                    t,
+                    cross.unit(),
+                    [b[1], b[2]],
+                    ray.dir,
+                    &self.material,
+                );
+                let hit_record = hit_record.with_footprint(ray, cross.length().sqrt().recip());
+                Some(hit_record.with_tangent(v1 - *v0))
+            }
+            Shape::Mesh { mesh } => {
+                let hit = mesh.hit(ray, t_min, t_max)?;
+                let hit_record = HitRecord::with_shading_normal(
+                    hit.p,
+                    hit.t,
+                    hit.geometric_normal,
+                    hit.shading_normal,
+                    hit.uv,
+                    ray.dir,
+                    &self.material,
+                );
+                Some(
+                    hit_record
+                        .with_footprint(ray, hit.uv_per_length)
+                        .with_tangent(hit.tangent),
+                )
+            }
+            Shape::Plane { point, normal } => {
+                let t = intersect_plane(ray, point, normal, t_min, t_max)?;
+                let p = ray.at(t);
+                // Texture coordinates are lengths along the plane.
+                let frame = Frame::from_z(normal);
+                let local = frame.to_local(&(p - point));
+                let hit_record =
+                    HitRecord::new(p, t, *normal, [local.x, local.y], ray.dir, &self.material);
+                Some(hit_record.with_footprint(ray, 1.0).with_tangent(frame.x))
+            }
+            Shape::Quad { corner, u, v } => {
+                let n = u.cross(v);
+                let t = intersect_plane(ray, corner, &n, t_min, t_max)?;
+                let p = ray.at(t);
+                // Coordinates of the hit along the edges.
+                let planar = p - corner;
+                let w = n / n.length_squared();
+                let alpha = w.dot(&planar.cross(v));
+                let beta = w.dot(&u.cross(&planar));
+                if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
                     return None;
                 }
-                let sqrtd = discriminant.sqrt();
-                let mut root = (-half_b - sqrtd) / a;
-                if root < t_min || t_max < root {
-                    root = (-half_b + sqrtd) / a;
-                    if root < t_min || t_max < root {
-                        return None;
-                    }
+                let hit_record =
+                    HitRecord::new(p, t, n.unit(), [alpha, beta], ray.dir, &self.material);
+                let hit_record = hit_record.with_footprint(ray, n.length().sqrt().recip());
+                Some(hit_record.with_tangent(*u))
+            }
+            Shape::Disk {
+                center,
+                normal,
+                radius,
+            } => {
+                let t = intersect_plane(ray, center, normal, t_min, t_max)?;
+                let p = ray.at(t);
+                let frame = Frame::from_z(normal);
+                let local = frame.to_local(&(p - center)) / radius;
+                if local.x * local.x + local.y * local.y > 1.0 {
+                    return None;
                 }
-                let p = ray.at(root);
-                Some(HitRecord::new(
+                // The bounding square of the disk maps to [0, 1]^2.
+                let uv = [0.5 * (local.x + 1.0), 0.5 * (local.y + 1.0)];
+                let hit_record = HitRecord::new(p, t, *normal, uv, ray.dir, &self.material);
+                Some(
+                    hit_record
+                        .with_footprint(ray, 0.5 / radius)
+                        .with_tangent(frame.x),
+                )
+            }
+            Shape::Box { min, max } => {
+                let (t, axis, outward) = intersect_box(ray, min, max, t_min, t_max)?;
+                let p = ray.at(t);
+                let mut normal = [0.0; 3];
+                normal[axis] = outward;
+                // Each face maps to [0, 1]^2, `u` along the next axis and `v`
+                // along the one after.
+                let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+                let d = max - *min;
+                let uv = [(p[a] - min[a]) / d[a], (p[b] - min[b]) / d[b]];
+                let mut tangent = [0.0; 3];
+                tangent[a] = 1.0;
+                let hit_record = HitRecord::new(
                     p,
-                    root,
-                    (p - center) / radius,
+                    t,
+                    Vec3::of(normal[0], normal[1], normal[2]),
+                    uv,
                     ray.dir,
                     &self.material,
-                ))
+                );
+                let hit_record = hit_record.with_footprint(ray, (d[a] * d[b]).sqrt().recip());
+                Some(hit_record.with_tangent(Vec3::of(tangent[0], tangent[1], tangent[2])))
             }
         }
     }
 }
 
+/// Distance along `ray` to the plane through `point` perpendicular to
+/// `normal`, if it lies in `[t_min, t_max]`.
+fn intersect_plane(ray: &Ray, point: &Vec3, normal: &Vec3, t_min: f32, t_max: f32) -> Option<f32> {
+    let denominator = normal.dot(&ray.dir);
+    // Parallel rays never hit, rays in the plane don't see it.
+    if denominator == 0.0 {
+        return None;
+    }
+    let t = (*point - ray.base).dot(normal) / denominator;
+    (t_min..=t_max).contains(&t).then_some(t)
+}
+
+/// First crossing of `ray` with the surface of the box in `[t_min, t_max]`,
+/// the axis of the face crossed and the sign of its outward normal.
+fn intersect_box(
+    ray: &Ray,
+    min: &Vec3,
+    max: &Vec3,
+    t_min: f32,
+    t_max: f32,
+) -> Option<(f32, usize, f32)> {
+    let (mut t_enter, mut enter_axis) = (f32::NEG_INFINITY, 0);
+    let (mut t_exit, mut exit_axis) = (f32::INFINITY, 0);
+    for axis in 0..3 {
+        let inverse = 1.0 / ray.dir[axis];
+        let a = (min[axis] - ray.base[axis]) * inverse;
+        let b = (max[axis] - ray.base[axis]) * inverse;
+        // f32::min/max ignore the NaN of 0 * inf for rays in a face plane.
+        if a.min(b) > t_enter {
+            (t_enter, enter_axis) = (a.min(b), axis);
+        }
+        if a.max(b) < t_exit {
+            (t_exit, exit_axis) = (a.max(b), axis);
+        }
+    }
+    if t_enter > t_exit {
+        return None;
+    }
+    // Entering faces oppose the ray, exiting ones point along it.
+    if (t_min..=t_max).contains(&t_enter) {
+        Some((t_enter, enter_axis, -ray.dir[enter_axis].signum()))
+    } else if (t_min..=t_max).contains(&t_exit) {
+        Some((t_exit, exit_axis, ray.dir[exit_axis].signum()))
+    } else {
+        None
+    }
+}
+
+/// Longitude and latitude of a point on the unit sphere, both in [0, 1].
+/// `v` grows from the bottom (-y) to the top, `u` around the y axis starting
+/// at -x.
+fn sphere_uv(outward_normal: &Vec3) -> [f32; 2] {
+    let theta = (-outward_normal.y).clamp(-1.0, 1.0).acos();
+    let phi = (-outward_normal.z).atan2(outward_normal.x) + std::f32::consts::PI;
+    [
+        phi / (2.0 * std::f32::consts::PI),
+        theta / std::f32::consts::PI,
+    ]
+}
+
 trait Hittable {
-    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord>;
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord<'_>>;
 }
 
 struct HitRecord<'a> {
     p: Vec3,
     normal: Vec3,
+    /// Facing the same side as `normal`.
+    geometric_normal: Vec3,
+    uv: [f32; 2],
+    /// Width of the ray's cone at the hit, in texture coordinates.
+    uv_width: f32,
+    /// Direction in which `u` grows, not normalized. Orients anisotropic
+    /// materials.
+    tangent: Vec3,
     t: f32,
     face: FACE,
     material: &'a Material,
 }
 
 impl<'a> HitRecord<'a> {
-    fn new(p: Vec3, t: f32, unaligned_normal: Vec3, ray_dir: Vec3, material: &'a Material) -> Self {
-        let tmp = if ray_dir.dot(&unaligned_normal) < 0.0 {
-            (FACE::FRONT, unaligned_normal)
+    fn new(
+        p: Vec3,
+        t: f32,
+        unaligned_normal: Vec3,
+        uv: [f32; 2],
+        ray_dir: Vec3,
+        material: &'a Material,
+    ) -> Self {
+        Self::with_shading_normal(
+            p,
+            t,
+            unaligned_normal,
+            unaligned_normal,
+            uv,
+            ray_dir,
+            material,
+        )
+    }
+
+    /// The face is decided by the geometric normal, the shading normal is
+    /// flipped onto the same side as the (aligned) geometric normal.
+    fn with_shading_normal(
+        p: Vec3,
+        t: f32,
+        geometric_normal: Vec3,
+        shading_normal: Vec3,
+        uv: [f32; 2],
+        ray_dir: Vec3,
+        material: &'a Material,
+    ) -> Self {
+        let (face, geometric_normal) = if ray_dir.dot(&geometric_normal) < 0.0 {
+            (FACE::FRONT, geometric_normal)
         } else {
-            (FACE::BACK, -unaligned_normal)
+            (FACE::BACK, -geometric_normal)
+        };
+        let normal = if shading_normal.dot(&geometric_normal) < 0.0 {
+            -shading_normal
+        } else {
+            shading_normal
         };
         HitRecord {
             p,
-            normal: tmp.1,
+            normal,
+            geometric_normal,
+            uv,
+            uv_width: 0.0,
+            tangent: Vec3::zero(),
             t,
-            face: tmp.0,
+            face,
             material,
         }
     }
+
+    /// Frame with z along the shading normal, and the direction back along
+    /// `ray` in it.
+    fn shading_frame(&self, ray: &Ray) -> (Frame, Vec3) {
+        let frame = Frame::from_z_and_tangent(&self.normal, &self.tangent);
+        let wo = frame.to_local(&-ray.dir.unit());
+        (frame, wo)
+    }
+
+    fn with_tangent(mut self, tangent: Vec3) -> Self {
+        self.tangent = tangent;
+        self
+    }
+
+    fn color_of(&self, texture: &Texture) -> Color {
+        texture.value(self.uv, self.uv_width, &self.p)
+    }
+
+    /// Moves the hit from an instanced scene into the instancing one.
+    fn transformed(mut self, transform: &Transform) -> Self {
+        self.p = transform.point(&self.p);
+        self.normal = transform.normal(&self.normal).unit();
+        self.geometric_normal = transform.normal(&self.geometric_normal).unit();
+        self.tangent = transform.vector(&self.tangent);
+        self
+    }
+
+    fn bsdf_of(&self, principled: &Principled) -> PrincipledBsdf {
+        let entering = matches!(self.face, FACE::FRONT);
+        principled.at(self.uv, self.uv_width, &self.p, entering)
+    }
+
+    /// Sets `uv_width` from the texture coordinates per unit of length on
+    /// the surface, assuming they stretch the same in all directions.
+    fn with_footprint(mut self, ray: &Ray, uv_per_length: f32) -> Self {
+        let length = ray.dir.length();
+        // At grazing angles the footprint is stretched along the surface.
+        // Going by its long side blurs rather than aliases.
+        let cos = self.geometric_normal.dot(&ray.dir).abs() / length;
+        self.uv_width = ray.cone.width_at(self.t * length) * uv_per_length / cos;
+        self
+    }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 enum FACE {
     FRONT,
     BACK,
 }
 
-const MAX_BOUNCE_DEPTH: i32 = 50;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        microfacet::tests::{assert_pdf_matches_histogram, direction},
+        random::MySmallRng,
+    };
+
+    const ROUGHNESSES: [f32; 4] = [0.1, 0.3, 0.6, 1.0];
+
+    /// A conductor that reflects all light at every angle, up to rounding.
+    fn mirror(roughness: f32) -> Material {
+        Material::Conductor {
+            ior: ComplexIor {
+                eta: Color::of(1.0, 1.0, 1.0),
+                k: Color::of(1.0e4, 1.0e4, 1.0e4),
+            },
+            roughness,
+        }
+    }
+
+    fn glass(roughness: f32) -> Material {
+        Material::Dieletric {
+            index_of_refraction: 1.5,
+            absorption: Color::zero(),
+            roughness,
+        }
+    }
+
+    /// Directions towards the viewer, from straight above the plane z = 0
+    /// to grazing, and the same below it.
+    fn outgoing() -> impl Iterator<Item = Vec3> {
+        let above = [1.0, 0.8, 0.5, 0.2].map(|cos| direction(cos, 0.7));
+        above
+            .into_iter()
+            .chain(above.map(|w| Vec3::of(w.x, w.y, -w.z)))
+    }
+
+    /// A ray from `wo` hitting the plane z = 0 at the origin, on its front
+    /// face from above and on its back face from below.
+    fn hit(material: &Material, wo: Vec3) -> (Ray, HitRecord<'_>) {
+        let ray = Ray::new(wo, -wo);
+        let normal = Vec3::of(0.0, 0.0, 1.0);
+        let hit_record = HitRecord::new(Vec3::zero(), 1.0, normal, [0.0, 0.0], ray.dir, material);
+        (ray, hit_record)
+    }
 
-pub fn ray_color(ray: &Ray, scene: &Scene, depth: i32, rng: &mut impl MyRng) -> Color {
-    if depth == MAX_BOUNCE_DEPTH {
-        return Color::zero();
+    /// Scatters once and checks the weight and density against `evaluate`
+    /// and `pdf` for the direction picked. The direction, weight and density,
+    /// `None` for absorbed samples.
+    fn scatter(
+        material: &Material,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        rng: &mut MySmallRng,
+    ) -> Option<(Vec3, Color, f32)> {
+        let result = material.scatter(ray, hit_record, rng)?;
+        let dir = result.ray_out.dir;
+        let pdf = result.pdf.expect("rough surfaces scatter with a density");
+        let weight = material.evaluate(ray, hit_record, &dir) / material.pdf(ray, hit_record, &dir);
+        for (a, b) in [
+            (result.attenuation.x, weight.x),
+            (result.attenuation.y, weight.y),
+            (result.attenuation.z, weight.z),
+        ] {
+            assert!(
+                (a - b).abs() <= 1.0e-3 * b,
+                "attenuation {a}, evaluate / pdf {b}"
+            );
+        }
+        Some((dir, result.attenuation, pdf))
     }
 
-    match scene.closest_hit(ray, 0.001, f32::MAX) {
-        Some(hit_record) => {
-            let scatter_result = hit_record.material.scatter(ray, &hit_record, rng);
-            match scatter_result {
-                Some(scatter_result) => {
-                    scatter_result.attenuation
-                        * ray_color(&scatter_result.ray_out, scene, depth + 1, rng)
+    /// Monte Carlo estimate of the fraction of light scattered, the mean
+    /// attenuation.
+    fn albedo(material: &Material, wo: Vec3) -> Color {
+        let (ray, hit_record) = hit(material, wo);
+        let mut rng = MySmallRng::from_seed(1);
+        let n = 100_000;
+        let total = (0..n)
+            .filter_map(|_| scatter(material, &ray, &hit_record, &mut rng))
+            .fold(Color::zero(), |sum, (_, attenuation, _)| sum + attenuation);
+        total / n as f32
+    }
+
+    fn assert_albedo_at_most(albedo: Color, most: f32, context: &str) {
+        for a in [albedo.x, albedo.y, albedo.z] {
+            assert!(a <= most + 1.0e-4, "albedo {a} above {most} for {context}");
+        }
+    }
+
+    #[test]
+    fn mirror_conductor_keeps_energy() {
+        for roughness in ROUGHNESSES {
+            let material = mirror(roughness);
+            for wo in outgoing().filter(|wo| wo.z > 0.0) {
+                let albedo = albedo(&material, wo);
+                assert_albedo_at_most(albedo, 1.0, &format!("{roughness}, {wo:?}"));
+                // Single scattering loses only what bounces between
+                // microfacets, next to nothing on smooth surfaces.
+                if roughness <= 0.1 {
+                    assert!(albedo.x >= 0.99, "albedo {albedo:?} for {wo:?}");
                 }
-                None => Color::zero(),
             }
         }
-        None => {
-            let unit = ray.dir.unit();
-            assert!(0.9999 <= unit.length() && unit.length() <= 1.00001);
-            let t = 0.5 * (unit.y + 1.0);
-            assert!(0.0 <= t && t <= 1.0000);
-            (1.0 - t) * Color::of(1.0, 1.0, 1.0) + t * Color::of(0.5, 0.7, 1.0)
+    }
+
+    #[test]
+    fn conductor_reflects_by_fresnel() {
+        let material = Material::Conductor {
+            ior: ComplexIor::GOLD,
+            roughness: 0.1,
+        };
+        let wo = Vec3::of(0.0, 0.0, 1.0);
+        let albedo = albedo(&material, wo);
+        let reflectance = ComplexIor::GOLD.reflectance(1.0);
+        for (a, r) in [
+            (albedo.x, reflectance.x),
+            (albedo.y, reflectance.y),
+            (albedo.z, reflectance.z),
+        ] {
+            assert!((a - r).abs() <= 0.01 * r, "albedo {a}, reflectance {r}");
         }
     }
+
+    #[test]
+    fn dielectric_reflects_or_transmits_everything() {
+        for roughness in ROUGHNESSES {
+            let material = glass(roughness);
+            // Into the glass and out of it, with total internal reflection.
+            for wo in outgoing() {
+                let albedo = albedo(&material, wo);
+                assert_albedo_at_most(albedo, 1.0, &format!("{roughness}, {wo:?}"));
+                if roughness <= 0.1 {
+                    assert!(albedo.x >= 0.99, "albedo {albedo:?} for {wo:?}");
+                }
+            }
+        }
+    }
+
+    fn assert_scatter_pdf_matches_histogram(material: &Material, wo: Vec3) {
+        let (ray, hit_record) = hit(material, wo);
+        assert_pdf_matches_histogram(
+            |rng| scatter(material, &ray, &hit_record, rng).map(|(dir, _, pdf)| (dir, pdf)),
+            |dir| material.pdf(&ray, &hit_record, dir),
+        );
+    }
+
+    #[test]
+    fn conductor_pdf_matches_histogram() {
+        assert_scatter_pdf_matches_histogram(&mirror(0.5), direction(0.6, 0.3));
+    }
+
+    #[test]
+    fn dielectric_pdf_matches_histogram() {
+        let wo = direction(0.6, 0.3);
+        assert_scatter_pdf_matches_histogram(&glass(0.5), wo);
+        assert_scatter_pdf_matches_histogram(&glass(0.5), Vec3::of(wo.x, wo.y, -wo.z));
+    }
 }
@@ -0,0 +1,1521 @@
+//! TOML scene description.
+//!
+//! ```toml
+//! background = "sky"          # optional, "sky", a colour like [0.0, 0.0, 0.0] or a map
+//! # background = { file = "studio.hdr", rotation = 0.0, intensity = 1.0 }
+//! #   equirectangular .hdr, .exr or .pfm, top row up, rotated in degrees around y
+//! medium = "haze"             # optional, fills the scene up to the surfaces rays hit
+//!
+//! [camera]
+//! look_from = [0.0, 0.0, 0.0]
+//! look_at = [0.0, 0.0, -1.0]
+//! up = [0.0, 1.0, 0.0]        # optional
+//! vertical_fov = 90.0         # degrees, optional
+//! aperture = 0.0              # optional
+//! focus_distance = 1.0        # optional, defaults to |look_at - look_from|
+//! shutter_open = 0.0          # optional, rays get random times in between
+//! shutter_close = 1.0         # optional, defaults to shutter_open, no blur
+//!
+//! [materials.ground]
+//! type = "lambertian"         # or "metal", "conductor", "dielectric", "principled",
+//!                             # "diffuse_light"
+//! albedo = [0.8, 0.8, 0.0]
+//!
+//! [materials.floor]
+//! type = "lambertian"
+//! [materials.floor.albedo]    # a colour or a texture
+//! type = "checker"            # or "uv_checker", "noise", "turbulence", "marble", "image"
+//! even = [0.2, 0.3, 0.1]      # colours or textures
+//! odd = [0.9, 0.9, 0.9]
+//! size = 1.0                  # optional
+//! # scale = 4.0               # noise frequency, optional
+//! # color = [1.0, 1.0, 1.0]   # noise colour, optional
+//! # file = "wood.png"         # image, .png, .ppm, .hdr, .pfm or .exr
+//! # wrap = "repeat"           # image, or "clamp", "mirror", optional
+//!
+//! [materials.bottle]
+//! type = "dielectric"
+//! index_of_refraction = 1.5
+//! tint = [0.6, 0.9, 0.7]      # optional, colour kept after tint_distance
+//! tint_distance = 1.0         # optional
+//! roughness = 0.0             # optional, in [0, 1], frosted above 0
+//!
+//! [materials.ring]
+//! type = "conductor"
+//! metal = "gold"              # or "copper", "aluminium", "silver"
+//! # eta = [0.2, 0.9, 1.1]     # instead of metal, complex index of refraction
+//! # k = [3.9, 2.5, 2.1]
+//! roughness = 0.2             # optional, in [0, 1], a mirror at 0
+//!
+//! [materials.car_paint]
+//! type = "principled"         # every key optional, numbers in [0, 1] or textures
+//! base_color = [0.6, 0.1, 0.1]
+//! metallic = 0.0
+//! roughness = 0.5
+//! anisotropy = 0.0            # stretches highlights along u
+//! specular = 0.5              # 4% reflectance, sets the index of refraction
+//! specular_tint = 0.0
+//! sheen = 0.0
+//! sheen_tint = 0.5
+//! clearcoat = 1.0
+//! clearcoat_roughness = 0.03
+//! transmission = 0.0          # glass tinted by base_color at 1
+//!
+//! [media.smoke]
+//! type = "homogeneous"
+//! absorption = [0.1, 0.1, 0.1] # optional, per unit of distance
+//! scattering = [0.8, 0.8, 0.8] # optional, per unit of distance
+//! asymmetry = 0.0             # optional, in (-1, 1), forward scattering above 0
+//! density = 1.0               # optional, scales absorption and scattering
+//!
+//! [media.cloud]
+//! type = "grid"               # density varying over a box, zero outside
+//! min = [-1.0, 0.0, -1.0]
+//! max = [1.0, 2.0, 1.0]
+//! file = "cloud.raw"          # little-endian 32 bit floats, x varying fastest, then y, z
+//! resolution = [64, 64, 64]   # voxels along x, y and z, optional with a texture
+//! # texture = { type = "turbulence", scale = 2.0 } # instead of file, at voxel centres
+//! cutoff = 0.0                # optional, clears lower values and stretches the rest
+//! emission = [0.0, 0.0, 0.0]  # optional, glow per unit of distance, like fire
+//! # absorption, scattering, asymmetry and density as above, all per unit of grid value
+//!
+//! [[shapes]]
+//! type = "sphere"             # or "triangle", "mesh", "obj"
+//! center = [0.0, -100.5, -1.0]
+//! radius = 100.0
+//! material = "ground"
+//!
+//! [[shapes]]
+//! type = "moving_sphere"      # blurred by the camera shutter
+//! center0 = [0.0, 0.0, -1.0]  # position at time0, held before it
+//! center1 = [0.0, 0.5, -1.0]  # position at time1, held after it
+//! time0 = 0.0                 # optional
+//! time1 = 1.0                 # optional
+//! radius = 0.5
+//! material = "ground"
+//!
+//! [[shapes]]
+//! type = "plane"              # infinite, never sampled as a light
+//! point = [0.0, -0.5, 0.0]
+//! normal = [0.0, 1.0, 0.0]
+//! material = "ground"
+//!
+//! [[shapes]]
+//! type = "quad"               # parallelogram, u x v faces the front
+//! corner = [-1.0, 2.0, -1.0]
+//! u = [2.0, 0.0, 0.0]
+//! v = [0.0, 0.0, 2.0]
+//! material = "ground"
+//!
+//! [[shapes]]
+//! type = "disk"
+//! center = [0.0, 2.0, 0.0]
+//! normal = [0.0, -1.0, 0.0]
+//! radius = 0.5
+//! material = "ground"
+//!
+//! [[shapes]]
+//! type = "box"                # axis-aligned
+//! min = [-0.5, -0.5, -1.5]
+//! max = [0.5, 0.5, -0.5]
+//! material = "ground"
+//!
+//! [[shapes]]
+//! type = "sphere"             # any shape but instances can bound a medium
+//! center = [0.0, 1.0, -1.0]
+//! radius = 0.5
+//! medium = "smoke"            # fills the closed shape
+//! # material = "glass"        # optional, an invisible surface without it
+//!
+//! [groups.tree]               # shapes that are placed together by instances
+//! [[groups.tree.shapes]]      # like top-level shapes, but no planes or instances
+//! type = "obj"
+//! file = "tree.obj"
+//!
+//! [[shapes]]
+//! type = "instance"           # shares the group's geometry
+//! group = "tree"
+//! scale = 2.0                 # optional, or [x, y, z]
+//! rotate = [0.0, 45.0, 0.0]   # optional, degrees around x, then y, then z
+//! translate = [3.0, 0.0, -2.0] # optional
+//!
+//! [[shapes]]
+//! type = "instance"
+//! group = "tree"
+//! keyframes = [               # instead of scale, rotate and translate,
+//!     { time = 0.0, rotate = [0.0, 0.0, 0.0] },  # interpolated linearly
+//!     { time = 1.0, rotate = [0.0, 90.0, 0.0], translate = [1.0, 0.0, 0.0] },
+//! ]
+//! ```
+//!
+//! Paths (`file` of `obj` shapes and image textures) are relative to the scene
+//! file.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use toml::{
+    Spanned,
+    de::{DeTable, DeValue},
+};
+
+use crate::{
+    bvh::Aabb,
+    environment::EnvironmentMap,
+    geo::{Keyframes, Pose, Vec3},
+    grid::VoxelGrid,
+    image_io::{self, ImageError},
+    medium::{GridMedium, HenyeyGreenstein, Medium},
+    mesh::Mesh,
+    microfacet::ComplexIor,
+    obj::ObjError,
+    principled::Principled,
+    render::{Camera, Color},
+    scene::{Background, Material, Scene},
+    texture::{MipMap, NoiseKind, Texture, WrapMode},
+};
+
+/// A scene together with the camera it is meant to be viewed from.
+pub struct SceneDescription {
+    pub scene: Scene,
+    pub camera: CameraDescription,
+}
+
+/// Camera parameters without the aspect ratio, which comes from the image.
+#[derive(Debug, Clone)]
+pub struct CameraDescription {
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
+    pub vertical_fov: f32,
+    pub aperture: f32,
+    pub focus_distance: f32,
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+impl Default for CameraDescription {
+    fn default() -> Self {
+        Self {
+            look_from: Vec3::zero(),
+            look_at: Vec3::of(0.0, 0.0, -1.0),
+            up: Vec3::of(0.0, 1.0, 0.0),
+            vertical_fov: 90.0,
+            aperture: 0.0,
+            focus_distance: 1.0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        }
+    }
+}
+
+impl CameraDescription {
+    pub fn to_camera(&self, aspect_ratio: f32) -> Camera {
+        Camera::new(
+            self.look_from,
+            self.look_at,
+            self.up,
+            self.vertical_fov,
+            aspect_ratio,
+        )
+        .with_defocus(self.aperture, self.focus_distance)
+        .with_shutter(self.shutter_open, self.shutter_close)
+    }
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io {
+        path: PathBuf,
+        cause: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    Obj {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        cause: ObjError,
+    },
+    Image {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        image: PathBuf,
+        cause: ImageError,
+    },
+    Grid {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        grid: PathBuf,
+        cause: std::io::Error,
+    },
+}
+
+impl Error for SceneError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SceneError::Io { cause, .. } => Some(cause),
+            SceneError::Parse { .. } => None,
+            SceneError::Obj { cause, .. } => Some(cause),
+            SceneError::Image { cause, .. } => Some(cause),
+            SceneError::Grid { cause, .. } => Some(cause),
+        }
+    }
+}
+
+impl Display for SceneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SceneError::Io { path, cause } => write!(f, "{}: {cause}", path.display()),
+            SceneError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{line}:{column}: {message}", path.display()),
+            SceneError::Obj {
+                path,
+                line,
+                column,
+                cause,
+            } => write!(f, "{}:{line}:{column}: {cause}", path.display()),
+            SceneError::Image {
+                path,
+                line,
+                column,
+                image,
+                cause,
+            } => write!(
+                f,
+                "{}:{line}:{column}: {}: {cause}",
+                path.display(),
+                image.display()
+            ),
+            SceneError::Grid {
+                path,
+                line,
+                column,
+                grid,
+                cause,
+            } => write!(
+                f,
+                "{}:{line}:{column}: {}: {cause}",
+                path.display(),
+                grid.display()
+            ),
+        }
+    }
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<SceneDescription, SceneError> {
+    let path = path.as_ref();
+    let source = std::fs::read_to_string(path).map_err(|cause| SceneError::Io {
+        path: path.to_owned(),
+        cause,
+    })?;
+    parse(&source, path)
+}
+
+/// Parses a scene description. `path` is used for error messages and to
+/// resolve relative paths inside the description.
+pub fn parse(source: &str, path: &Path) -> Result<SceneDescription, SceneError> {
+    let parser = Parser { source, path };
+    let root = DeTable::parse(source)
+        .map_err(|e| parser.error(e.span().unwrap_or(0..0), e.message().to_string()))?;
+    parser.scene(&root)
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    path: &'a Path,
+}
+
+type Value<'i> = Spanned<DeValue<'i>>;
+
+/// A TOML table together with its position, for error messages.
+struct Table<'p, 'i> {
+    parser: &'p Parser<'p>,
+    span: Range<usize>,
+    entries: &'p DeTable<'i>,
+}
+
+impl<'a> Parser<'a> {
+    fn location(&self, offset: usize) -> (usize, usize) {
+        let before = &self.source[..offset.min(self.source.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        (line, column)
+    }
+
+    fn error(&self, span: Range<usize>, message: String) -> SceneError {
+        let (line, column) = self.location(span.start);
+        SceneError::Parse {
+            path: self.path.to_owned(),
+            line,
+            column,
+            message,
+        }
+    }
+
+    fn table<'p, 'i>(
+        &'p self,
+        value: &'p Value<'i>,
+        what: &str,
+    ) -> Result<Table<'p, 'i>, SceneError> {
+        match value.get_ref() {
+            DeValue::Table(entries) => Ok(Table {
+                parser: self,
+                span: value.span(),
+                entries,
+            }),
+            other => Err(self.error(
+                value.span(),
+                format!("{what} must be a table, found {}", other.type_str()),
+            )),
+        }
+    }
+
+    fn f32(&self, value: &Value<'_>, what: &str) -> Result<f32, SceneError> {
+        let parsed = match value.get_ref() {
+            DeValue::Float(f) => f.as_str().parse::<f32>().ok(),
+            DeValue::Integer(i) => i64::from_str_radix(i.as_str(), i.radix())
+                .ok()
+                .map(|i| i as f32),
+            _ => None,
+        };
+        match parsed {
+            Some(v) if v.is_finite() => Ok(v),
+            _ => Err(self.error(value.span(), format!("{what} must be a finite number"))),
+        }
+    }
+
+    fn vec3(&self, value: &Value<'_>, what: &str) -> Result<Vec3, SceneError> {
+        let v = self.f32_array(value, what)?;
+        match v[..] {
+            [x, y, z] => Ok(Vec3::of(x, y, z)),
+            _ => Err(self.error(
+                value.span(),
+                format!(
+                    "{what} must be a vector of 3 numbers, found {} numbers",
+                    v.len()
+                ),
+            )),
+        }
+    }
+
+    fn f32_array(&self, value: &Value<'_>, what: &str) -> Result<Vec<f32>, SceneError> {
+        let DeValue::Array(items) = value.get_ref() else {
+            return Err(self.error(value.span(), format!("{what} must be an array of numbers")));
+        };
+        items.iter().map(|item| self.f32(item, what)).collect()
+    }
+
+    fn array<'p, 'i>(
+        &self,
+        value: &'p Value<'i>,
+        what: &str,
+    ) -> Result<&'p [Value<'i>], SceneError> {
+        match value.get_ref() {
+            DeValue::Array(items) => Ok(items),
+            other => Err(self.error(
+                value.span(),
+                format!("{what} must be an array, found {}", other.type_str()),
+            )),
+        }
+    }
+
+    fn string<'p>(&self, value: &'p Value<'_>, what: &str) -> Result<&'p str, SceneError> {
+        match value.get_ref() {
+            DeValue::String(s) => Ok(s),
+            other => Err(self.error(
+                value.span(),
+                format!("{what} must be a string, found {}", other.type_str()),
+            )),
+        }
+    }
+
+    fn scene(&self, root: &Spanned<DeTable<'_>>) -> Result<SceneDescription, SceneError> {
+        let root = Table {
+            parser: self,
+            span: root.span(),
+            entries: root.get_ref(),
+        };
+        root.check_keys(&[
+            "camera",
+            "background",
+            "medium",
+            "materials",
+            "media",
+            "groups",
+            "shapes",
+        ])?;
+
+        let camera = match root.get("camera") {
+            Some(camera) => self.camera(&self.table(camera, "camera")?)?,
+            None => CameraDescription::default(),
+        };
+
+        let mut materials = HashMap::new();
+        if let Some(value) = root.get("materials") {
+            for (name, value) in self.table(value, "materials")?.entries.iter() {
+                let material = self.material(&self.table(value, "a material")?)?;
+                materials.insert(name.get_ref().to_string(), material);
+            }
+        }
+
+        let mut media = HashMap::new();
+        if let Some(value) = root.get("media") {
+            for (name, value) in self.table(value, "media")?.entries.iter() {
+                let medium = self.medium(&self.table(value, "a medium")?)?;
+                media.insert(name.get_ref().to_string(), medium);
+            }
+        }
+
+        let mut groups = HashMap::new();
+        if let Some(value) = root.get("groups") {
+            for (name, value) in self.table(value, "groups")?.entries.iter() {
+                let table = self.table(value, "a group")?;
+                table.check_keys(&["shapes"])?;
+                let mut group = Scene::new();
+                for shape in self.array(table.required("shapes")?, "shapes")? {
+                    let shape = self.table(shape, "a shape")?;
+                    match shape.string("type")? {
+                        "instance" => return Err(shape.error_at("type", "groups can't nest")),
+                        "plane" => {
+                            return Err(shape.error_at("type", "groups can't contain planes"));
+                        }
+                        _ => self.shape(&shape, &materials, &media, &groups, &mut group)?,
+                    }
+                }
+                groups.insert(name.get_ref().to_string(), Arc::new(group));
+            }
+        }
+
+        let mut scene = Scene::new();
+        if let Some(value) = root.get("background") {
+            scene.set_background(self.background(value)?);
+        }
+        if let Some(value) = root.get("medium") {
+            let name = self.string(value, "medium")?;
+            let medium = media
+                .get(name)
+                .ok_or_else(|| self.error(value.span(), format!("unknown medium '{name}'")))?;
+            scene.set_medium(medium.clone());
+        }
+        if let Some(shapes) = root.get("shapes") {
+            for shape in self.array(shapes, "shapes")? {
+                let shape = self.table(shape, "a shape")?;
+                self.shape(&shape, &materials, &media, &groups, &mut scene)?;
+            }
+        }
+        Ok(SceneDescription { scene, camera })
+    }
+
+    fn background(&self, value: &Value<'_>) -> Result<Background, SceneError> {
+        match value.get_ref() {
+            DeValue::String(name) => {
+                return match name.as_ref() {
+                    "sky" => Ok(Background::Sky),
+                    other => Err(self.error(
+                        value.span(),
+                        format!(
+                            "unknown background '{other}', expected \"sky\", a colour or a map"
+                        ),
+                    )),
+                };
+            }
+            DeValue::Table(_) => {
+                let table = self.table(value, "background")?;
+                table.check_keys(&["file", "rotation", "intensity"])?;
+                let intensity = table.optional_f32("intensity")?.unwrap_or(1.0);
+                if intensity < 0.0 {
+                    return Err(table.error_at("intensity", "intensity must not be negative"));
+                }
+                let rotation = table.optional_f32("rotation")?.unwrap_or(0.0);
+                let file = self.relative_path(table.string("file")?);
+                let image = image_io::load(&file).map_err(|cause| {
+                    let (line, column) = self.location(table.span_of("file").start);
+                    SceneError::Image {
+                        path: self.path.to_owned(),
+                        line,
+                        column,
+                        image: file.clone(),
+                        cause,
+                    }
+                })?;
+                let map = EnvironmentMap::new(image, rotation, intensity);
+                return Ok(Background::Map(Arc::new(map)));
+            }
+            _ => {}
+        }
+        let color = self.vec3(value, "background")?;
+        if color.x < 0.0 || color.y < 0.0 || color.z < 0.0 {
+            return Err(self.error(
+                value.span(),
+                "background must not have negative components".into(),
+            ));
+        }
+        Ok(Background::Solid(color))
+    }
+
+    fn camera(&self, table: &Table<'_, '_>) -> Result<CameraDescription, SceneError> {
+        table.check_keys(&[
+            "look_from",
+            "look_at",
+            "up",
+            "vertical_fov",
+            "aperture",
+            "focus_distance",
+            "shutter_open",
+            "shutter_close",
+        ])?;
+        let defaults = CameraDescription::default();
+        let look_from = table
+            .optional_vec3("look_from")?
+            .unwrap_or(defaults.look_from);
+        let look_at = table.optional_vec3("look_at")?.unwrap_or(defaults.look_at);
+        if (look_at - look_from).near_zero() {
+            return Err(table.error("look_from and look_at must differ"));
+        }
+        let up = table.optional_vec3("up")?.unwrap_or(defaults.up);
+        // Sine of the angle between up and the view direction.
+        if up.cross(&(look_at - look_from).unit()).length() <= 1.0e-6 * up.length() {
+            let message = "up must not be zero or parallel to the view direction";
+            return Err(match table.get("up") {
+                Some(_) => table.error_at("up", message),
+                None => table.error(message),
+            });
+        }
+        let vertical_fov = table
+            .optional_f32("vertical_fov")?
+            .unwrap_or(defaults.vertical_fov);
+        if vertical_fov <= 0.0 || vertical_fov >= 180.0 {
+            return Err(table.error_at(
+                "vertical_fov",
+                "vertical_fov must be between 0 and 180 degrees",
+            ));
+        }
+        let aperture = table.optional_f32("aperture")?.unwrap_or(0.0);
+        if aperture < 0.0 {
+            return Err(table.error_at("aperture", "aperture must not be negative"));
+        }
+        let focus_distance = table
+            .optional_f32("focus_distance")?
+            .unwrap_or((look_at - look_from).length());
+        if focus_distance <= 0.0 {
+            return Err(table.error_at("focus_distance", "focus_distance must be positive"));
+        }
+        let shutter_open = table.optional_f32("shutter_open")?.unwrap_or(0.0);
+        let shutter_close = table.optional_f32("shutter_close")?.unwrap_or(shutter_open);
+        if shutter_close < shutter_open {
+            return Err(table.error_at(
+                "shutter_close",
+                "shutter_close must not be before shutter_open",
+            ));
+        }
+        Ok(CameraDescription {
+            look_from,
+            look_at,
+            up,
+            vertical_fov,
+            aperture,
+            focus_distance,
+            shutter_open,
+            shutter_close,
+        })
+    }
+
+    fn material(&self, table: &Table<'_, '_>) -> Result<Material, SceneError> {
+        let roughness = || {
+            let roughness = table.optional_f32("roughness")?.unwrap_or(0.0);
+            if !(0.0..=1.0).contains(&roughness) {
+                return Err(table.error_at("roughness", "roughness must be between 0 and 1"));
+            }
+            Ok(roughness)
+        };
+        match table.string("type")? {
+            "lambertian" => {
+                table.check_keys(&["type", "albedo"])?;
+                Ok(Material::Lambertian {
+                    albedo: table.texture("albedo")?,
+                })
+            }
+            "metal" => {
+                table.check_keys(&["type", "albedo", "fuzz"])?;
+                let fuzz = table.optional_f32("fuzz")?.unwrap_or(0.0);
+                if !(0.0..=1.0).contains(&fuzz) {
+                    return Err(table.error_at("fuzz", "fuzz must be between 0 and 1"));
+                }
+                Ok(Material::Metal {
+                    albedo: table.texture("albedo")?,
+                    fuzz,
+                })
+            }
+            "conductor" => {
+                table.check_keys(&["type", "metal", "eta", "k", "roughness"])?;
+                let ior = match table.get("metal") {
+                    Some(_) => {
+                        if table.get("eta").is_some() || table.get("k").is_some() {
+                            return Err(table.error_at("metal", "give either metal or eta and k"));
+                        }
+                        let metal = table.string("metal")?;
+                        ComplexIor::preset(metal).ok_or_else(|| {
+                            table.error_at(
+                                "metal",
+                                &format!(
+                                    "unknown metal '{metal}', expected gold, copper, aluminium \
+                                     or silver"
+                                ),
+                            )
+                        })?
+                    }
+                    None => {
+                        let eta = table.vec3("eta")?;
+                        if [eta.x, eta.y, eta.z].iter().any(|c| *c <= 0.0) {
+                            return Err(table.error_at("eta", "eta components must be positive"));
+                        }
+                        ComplexIor {
+                            eta,
+                            k: table.color("k")?,
+                        }
+                    }
+                };
+                Ok(Material::Conductor {
+                    ior,
+                    roughness: roughness()?,
+                })
+            }
+            "dielectric" => {
+                table.check_keys(&[
+                    "type",
+                    "index_of_refraction",
+                    "tint",
+                    "tint_distance",
+                    "roughness",
+                ])?;
+                let index_of_refraction = table.f32("index_of_refraction")?;
+                if index_of_refraction <= 0.0 {
+                    return Err(table.error_at(
+                        "index_of_refraction",
+                        "index_of_refraction must be positive",
+                    ));
+                }
+                // Light that travels `tint_distance` through the glass keeps
+                // `tint` of its energy.
+                let tint = table
+                    .optional_vec3("tint")?
+                    .unwrap_or(Color::of(1.0, 1.0, 1.0));
+                if [tint.x, tint.y, tint.z]
+                    .iter()
+                    .any(|c| *c <= 0.0 || *c > 1.0)
+                {
+                    return Err(table.error_at("tint", "tint components must be in (0, 1]"));
+                }
+                let tint_distance = table.optional_f32("tint_distance")?.unwrap_or(1.0);
+                if tint_distance <= 0.0 {
+                    return Err(table.error_at("tint_distance", "tint_distance must be positive"));
+                }
+                let coefficient = |c: f32| -c.ln() / tint_distance;
+                Ok(Material::Dieletric {
+                    index_of_refraction,
+                    absorption: Color::of(
+                        coefficient(tint.x),
+                        coefficient(tint.y),
+                        coefficient(tint.z),
+                    ),
+                    roughness: roughness()?,
+                })
+            }
+            "principled" => {
+                const SCALARS: [&str; 10] = [
+                    "metallic",
+                    "roughness",
+                    "anisotropy",
+                    "specular",
+                    "specular_tint",
+                    "sheen",
+                    "sheen_tint",
+                    "clearcoat",
+                    "clearcoat_roughness",
+                    "transmission",
+                ];
+                let mut keys = vec!["type", "base_color"];
+                keys.extend(SCALARS);
+                table.check_keys(&keys)?;
+                let mut principled = Principled::default();
+                if let Some(base_color) = table.optional_texture("base_color")? {
+                    principled.base_color = base_color;
+                }
+                for key in SCALARS {
+                    let Some(texture) = table.optional_scalar_texture(key)? else {
+                        continue;
+                    };
+                    let field = match key {
+                        "metallic" => &mut principled.metallic,
+                        "roughness" => &mut principled.roughness,
+                        "anisotropy" => &mut principled.anisotropy,
+                        "specular" => &mut principled.specular,
+                        "specular_tint" => &mut principled.specular_tint,
+                        "sheen" => &mut principled.sheen,
+                        "sheen_tint" => &mut principled.sheen_tint,
+                        "clearcoat" => &mut principled.clearcoat,
+                        "clearcoat_roughness" => &mut principled.clearcoat_roughness,
+                        _ => &mut principled.transmission,
+                    };
+                    *field = texture;
+                }
+                Ok(Material::Principled(Box::new(principled)))
+            }
+            "diffuse_light" => {
+                table.check_keys(&["type", "emit"])?;
+                Ok(Material::DiffuseLight {
+                    emit: table.color("emit")?,
+                })
+            }
+            other => Err(table.error_at(
+                "type",
+                &format!(
+                    "unknown material type '{other}', expected lambertian, metal, conductor, \
+                     dielectric, principled or diffuse_light"
+                ),
+            )),
+        }
+    }
+
+    fn medium(&self, table: &Table<'_, '_>) -> Result<Medium, SceneError> {
+        let coefficient = |key: &str| -> Result<Color, SceneError> {
+            Ok(match table.get(key) {
+                Some(_) => table.color(key)?,
+                None => Color::zero(),
+            })
+        };
+        let density = table.optional_f32("density")?.unwrap_or(1.0);
+        if density < 0.0 {
+            return Err(table.error_at("density", "density must not be negative"));
+        }
+        let asymmetry = table.optional_f32("asymmetry")?.unwrap_or(0.0);
+        if asymmetry <= -1.0 || asymmetry >= 1.0 {
+            return Err(
+                table.error_at("asymmetry", "asymmetry must be between -1 and 1, exclusive")
+            );
+        }
+        let phase = HenyeyGreenstein::new(asymmetry);
+        match table.string("type")? {
+            "homogeneous" => {
+                table.check_keys(&["type", "absorption", "scattering", "asymmetry", "density"])?;
+                Ok(Medium::Homogeneous {
+                    absorption: density * coefficient("absorption")?,
+                    scattering: density * coefficient("scattering")?,
+                    phase,
+                })
+            }
+            "grid" => {
+                table.check_keys(&[
+                    "type",
+                    "min",
+                    "max",
+                    "file",
+                    "texture",
+                    "resolution",
+                    "cutoff",
+                    "absorption",
+                    "scattering",
+                    "emission",
+                    "asymmetry",
+                    "density",
+                ])?;
+                let (min, max) = (table.vec3("min")?, table.vec3("max")?);
+                if min.x >= max.x || min.y >= max.y || min.z >= max.z {
+                    return Err(table.error("a grid needs min below max on every axis"));
+                }
+                let cutoff = table.optional_f32("cutoff")?.unwrap_or(0.0);
+                if !(0.0..1.0).contains(&cutoff) {
+                    return Err(table.error_at("cutoff", "cutoff must be in [0, 1)"));
+                }
+                let grid = match (table.get("file"), table.get("texture")) {
+                    (Some(_), None) => {
+                        let file = self.relative_path(table.string("file")?);
+                        let resolution = self.resolution(table.required("resolution")?)?;
+                        VoxelGrid::load_raw(&file, resolution).map_err(|cause| {
+                            let (line, column) = self.location(table.span_of("file").start);
+                            SceneError::Grid {
+                                path: self.path.to_owned(),
+                                line,
+                                column,
+                                grid: file.clone(),
+                                cause,
+                            }
+                        })?
+                    }
+                    (None, Some(_)) => {
+                        let texture = table.texture("texture")?;
+                        let resolution = match table.get("resolution") {
+                            Some(value) => self.resolution(value)?,
+                            None => [64; 3],
+                        };
+                        let size = max - min;
+                        // Solid textures only look at the position.
+                        VoxelGrid::from_fn(resolution, |p| {
+                            let c = texture.value([0.0; 2], 0.0, &(min + *p * size));
+                            (c.x + c.y + c.z) / 3.0
+                        })
+                        .expect("resolutions are positive")
+                    }
+                    _ => return Err(table.error("a grid needs either a file or a texture")),
+                };
+                // Thin wisps vanish, the rest keeps its range.
+                let grid = if cutoff > 0.0 {
+                    grid.map(|v| ((v - cutoff) / (1.0 - cutoff)).max(0.0))
+                } else {
+                    grid
+                };
+                Ok(Medium::Grid(GridMedium::new(
+                    Arc::new(grid),
+                    Aabb::of(min, max),
+                    density * coefficient("absorption")?,
+                    density * coefficient("scattering")?,
+                    density * coefficient("emission")?,
+                    phase,
+                )))
+            }
+            other => Err(table.error_at(
+                "type",
+                &format!("unknown medium type '{other}', expected homogeneous or grid"),
+            )),
+        }
+    }
+
+    /// Voxels along x, y and z.
+    fn resolution(&self, value: &Value<'_>) -> Result<[usize; 3], SceneError> {
+        let v = self.vec3(value, "resolution")?;
+        let count =
+            |n: f32| (n.fract() == 0.0 && (1.0..=4096.0).contains(&n)).then_some(n as usize);
+        match (count(v.x), count(v.y), count(v.z)) {
+            (Some(x), Some(y), Some(z)) => Ok([x, y, z]),
+            _ => Err(self.error(
+                value.span(),
+                "resolution must be whole numbers from 1 to 4096".into(),
+            )),
+        }
+    }
+
+    /// A number in [0, 1], or a table describing a texture whose channels
+    /// are averaged.
+    fn scalar_texture(&self, value: &Value<'_>, what: &str) -> Result<Texture, SceneError> {
+        if matches!(value.get_ref(), DeValue::Table(_)) {
+            return self.texture(value, what);
+        }
+        let v = self.f32(value, what)?;
+        if !(0.0..=1.0).contains(&v) {
+            return Err(self.error(value.span(), format!("{what} must be between 0 and 1")));
+        }
+        Ok(Texture::Solid(Color::of(v, v, v)))
+    }
+
+    /// A colour, or a table describing a texture.
+    fn texture(&self, value: &Value<'_>, what: &str) -> Result<Texture, SceneError> {
+        if !matches!(value.get_ref(), DeValue::Table(_)) {
+            let color = self.vec3(value, what)?;
+            if color.x < 0.0 || color.y < 0.0 || color.z < 0.0 {
+                return Err(self.error(
+                    value.span(),
+                    format!("{what} must not have negative components"),
+                ));
+            }
+            return Ok(Texture::Solid(color));
+        }
+        let table = self.table(value, what)?;
+        let positive = |key: &str, default: f32| -> Result<f32, SceneError> {
+            let value = table.optional_f32(key)?.unwrap_or(default);
+            if value <= 0.0 {
+                return Err(table.error_at(key, &format!("{key} must be positive")));
+            }
+            Ok(value)
+        };
+        match table.string("type")? {
+            kind @ ("checker" | "uv_checker") => {
+                table.check_keys(&["type", "even", "odd", "size"])?;
+                let even = Box::new(table.texture("even")?);
+                let odd = Box::new(table.texture("odd")?);
+                let size = positive("size", 1.0)?;
+                Ok(if kind == "checker" {
+                    Texture::Checker { even, odd, size }
+                } else {
+                    Texture::UvChecker { even, odd, size }
+                })
+            }
+            kind @ ("noise" | "turbulence" | "marble") => {
+                table.check_keys(&["type", "scale", "color"])?;
+                let kind = match kind {
+                    "noise" => NoiseKind::Perlin,
+                    "turbulence" => NoiseKind::Turbulence,
+                    _ => NoiseKind::Marble,
+                };
+                let color = match table.get("color") {
+                    Some(_) => table.color("color")?,
+                    None => Color::of(1.0, 1.0, 1.0),
+                };
+                Ok(Texture::Noise {
+                    kind,
+                    scale: positive("scale", 1.0)?,
+                    color,
+                })
+            }
+            "image" => {
+                table.check_keys(&["type", "file", "wrap"])?;
+                let wrap = match table.get("wrap").map(|_| table.string("wrap")).transpose()? {
+                    None | Some("repeat") => WrapMode::Repeat,
+                    Some("clamp") => WrapMode::Clamp,
+                    Some("mirror") => WrapMode::Mirror,
+                    Some(other) => {
+                        let message = format!(
+                            "unknown wrap mode '{other}', expected repeat, clamp or mirror"
+                        );
+                        return Err(table.error_at("wrap", &message));
+                    }
+                };
+                let file = self.relative_path(table.string("file")?);
+                let image = image_io::load(&file).map_err(|cause| {
+                    let (line, column) = self.location(table.span_of("file").start);
+                    SceneError::Image {
+                        path: self.path.to_owned(),
+                        line,
+                        column,
+                        image: file.clone(),
+                        cause,
+                    }
+                })?;
+                Ok(Texture::Image {
+                    image: Arc::new(MipMap::new(image)),
+                    wrap,
+                })
+            }
+            other => Err(table.error_at(
+                "type",
+                &format!(
+                    "unknown texture type '{other}', expected checker, uv_checker, noise, turbulence, marble or image"
+                ),
+            )),
+        }
+    }
+
+    /// Paths in the scene file are relative to the scene file.
+    fn relative_path(&self, path: &str) -> PathBuf {
+        self.path.parent().unwrap_or(Path::new("")).join(path)
+    }
+
+    /// Adds a shape, filled with a medium if it names one.
+    fn shape(
+        &self,
+        table: &Table<'_, '_>,
+        materials: &HashMap<String, Material>,
+        media: &HashMap<String, Medium>,
+        groups: &HashMap<String, Arc<Scene>>,
+        scene: &mut Scene,
+    ) -> Result<(), SceneError> {
+        let Some(value) = table.get("medium") else {
+            return self.surface(table, materials, groups, scene);
+        };
+        let name = self.string(value, "medium")?;
+        let medium = media
+            .get(name)
+            .ok_or_else(|| table.error_at("medium", &format!("unknown medium '{name}'")))?;
+        let mut boundary = Scene::new();
+        self.surface(table, materials, groups, &mut boundary)?;
+        scene.add_volume(boundary, medium.clone());
+        Ok(())
+    }
+
+    /// Adds a shape, leaving its medium to `shape`.
+    fn surface(
+        &self,
+        table: &Table<'_, '_>,
+        materials: &HashMap<String, Material>,
+        groups: &HashMap<String, Arc<Scene>>,
+        scene: &mut Scene,
+    ) -> Result<(), SceneError> {
+        let material = |table: &Table<'_, '_>| -> Result<Material, SceneError> {
+            // Volumes without a material have no visible surface.
+            if table.get("material").is_none() && table.get("medium").is_some() {
+                return Ok(Material::Interface);
+            }
+            let name = table.string("material")?;
+            materials
+                .get(name)
+                .cloned()
+                .ok_or_else(|| table.error_at("material", &format!("unknown material '{name}'")))
+        };
+        let nonzero = |table: &Table<'_, '_>, key: &str| -> Result<Vec3, SceneError> {
+            let v = table.vec3(key)?;
+            if v.near_zero() {
+                return Err(table.error_at(key, &format!("{key} must not be zero")));
+            }
+            Ok(v)
+        };
+        match table.string("type")? {
+            "sphere" => {
+                table.check_keys(&["type", "material", "medium", "center", "radius"])?;
+                scene.add_sphere(
+                    table.vec3("center")?,
+                    table.f32("radius")?,
+                    material(table)?,
+                );
+            }
+            "moving_sphere" => {
+                table.check_keys(&[
+                    "type", "material", "medium", "center0", "center1", "time0", "time1", "radius",
+                ])?;
+                let time0 = table.optional_f32("time0")?.unwrap_or(0.0);
+                let time1 = table.optional_f32("time1")?.unwrap_or(1.0);
+                if time1 < time0 {
+                    return Err(table.error_at("time1", "time1 must not be before time0"));
+                }
+                scene.add_moving_sphere(
+                    (table.vec3("center0")?, time0),
+                    (table.vec3("center1")?, time1),
+                    table.f32("radius")?,
+                    material(table)?,
+                );
+            }
+            "triangle" => {
+                table.check_keys(&["type", "material", "medium", "vertices"])?;
+                let vertices = table.vec3_array("vertices")?;
+                let [v0, v1, v2] = vertices[..] else {
+                    return Err(table.error_at("vertices", "a triangle needs exactly 3 vertices"));
+                };
+                scene.add_triangle(v0, v1, v2, material(table)?);
+            }
+            "plane" => {
+                table.check_keys(&["type", "material", "medium", "point", "normal"])?;
+                scene.add_plane(
+                    table.vec3("point")?,
+                    nonzero(table, "normal")?,
+                    material(table)?,
+                );
+            }
+            "quad" => {
+                table.check_keys(&["type", "material", "medium", "corner", "u", "v"])?;
+                let (u, v) = (table.vec3("u")?, table.vec3("v")?);
+                if u.cross(&v).near_zero() {
+                    return Err(table.error("the edges u and v of a quad must not be parallel"));
+                }
+                scene.add_quad(table.vec3("corner")?, u, v, material(table)?);
+            }
+            "disk" => {
+                table.check_keys(&["type", "material", "medium", "center", "normal", "radius"])?;
+                let radius = table.f32("radius")?;
+                if radius <= 0.0 {
+                    return Err(table.error_at("radius", "radius must be positive"));
+                }
+                scene.add_disk(
+                    table.vec3("center")?,
+                    nonzero(table, "normal")?,
+                    radius,
+                    material(table)?,
+                );
+            }
+            "box" => {
+                table.check_keys(&["type", "material", "medium", "min", "max"])?;
+                let (min, max) = (table.vec3("min")?, table.vec3("max")?);
+                if min.x >= max.x || min.y >= max.y || min.z >= max.z {
+                    return Err(table.error("a box needs min below max on every axis"));
+                }
+                scene.add_box(min, max, material(table)?);
+            }
+            "instance" => {
+                table.check_keys(&[
+                    "type",
+                    "group",
+                    "scale",
+                    "rotate",
+                    "translate",
+                    "keyframes",
+                ])?;
+                let name = table.string("group")?;
+                let group = groups
+                    .get(name)
+                    .ok_or_else(|| table.error_at("group", &format!("unknown group '{name}'")))?;
+                if table.get("keyframes").is_none() {
+                    let transform = self.pose(table)?.transform().expect("scales are not zero");
+                    scene.add_instance(group.clone(), transform);
+                } else if ["scale", "rotate", "translate"]
+                    .iter()
+                    .any(|k| table.get(k).is_some())
+                {
+                    return Err(table.error_at(
+                        "keyframes",
+                        "give either keyframes or scale, rotate and translate",
+                    ));
+                } else {
+                    scene.add_animated_instance(group.clone(), self.keyframes(table)?);
+                }
+            }
+            "mesh" => {
+                table.check_keys(&[
+                    "type",
+                    "material",
+                    "medium",
+                    "positions",
+                    "triangles",
+                    "normals",
+                    "uvs",
+                ])?;
+                let mesh = self.mesh(table)?;
+                scene.add_mesh(Arc::new(mesh), material(table)?);
+            }
+            "obj" => {
+                table.check_keys(&["type", "material", "medium", "file"])?;
+                let file = self.relative_path(table.string("file")?);
+                let default_material =
+                    if table.get("material").is_some() || table.get("medium").is_some() {
+                        material(table)?
+                    } else {
+                        Material::Lambertian {
+                            albedo: Color::of(0.8, 0.8, 0.8).into(),
+                        }
+                    };
+                scene.add_obj(file, default_material).map_err(|cause| {
+                    let (line, column) = self.location(table.span_of("file").start);
+                    SceneError::Obj {
+                        path: self.path.to_owned(),
+                        line,
+                        column,
+                        cause,
+                    }
+                })?;
+            }
+            other => {
+                return Err(table.error_at(
+                    "type",
+                    &format!(
+                        "unknown shape type '{other}', expected sphere, moving_sphere, \
+                         triangle, plane, quad, disk, box, mesh, obj or instance"
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Scale, rotation and translation of an instance or keyframe.
+    fn pose(&self, table: &Table<'_, '_>) -> Result<Pose, SceneError> {
+        let scale = match table.get("scale") {
+            Some(value) if matches!(value.get_ref(), DeValue::Array(_)) => {
+                self.vec3(value, "scale")?
+            }
+            Some(value) => {
+                let factor = self.f32(value, "scale")?;
+                Vec3::of(factor, factor, factor)
+            }
+            None => Vec3::of(1.0, 1.0, 1.0),
+        };
+        if scale.x == 0.0 || scale.y == 0.0 || scale.z == 0.0 {
+            return Err(table.error_at("scale", "scale factors must not be zero"));
+        }
+        let defaults = Pose::default();
+        Ok(Pose {
+            scale,
+            rotate: table.optional_vec3("rotate")?.unwrap_or(defaults.rotate),
+            translate: table
+                .optional_vec3("translate")?
+                .unwrap_or(defaults.translate),
+        })
+    }
+
+    fn keyframes(&self, table: &Table<'_, '_>) -> Result<Keyframes, SceneError> {
+        let mut keys = vec![];
+        for value in self.array(table.required("keyframes")?, "keyframes")? {
+            let keyframe = self.table(value, "a keyframe")?;
+            keyframe.check_keys(&["time", "scale", "rotate", "translate"])?;
+            keys.push((keyframe.f32("time")?, self.pose(&keyframe)?));
+        }
+        Keyframes::new(keys).ok_or_else(|| {
+            table.error_at(
+                "keyframes",
+                "keyframes need increasing times and scale factors that keep their signs",
+            )
+        })
+    }
+
+    fn mesh(&self, table: &Table<'_, '_>) -> Result<Mesh, SceneError> {
+        let positions = table.vec3_array("positions")?;
+        let mut triangles = vec![];
+        let triangle_values = self.array(table.required("triangles")?, "triangles")?;
+        if triangle_values.is_empty() {
+            return Err(table.error_at("triangles", "a mesh needs at least one triangle"));
+        }
+        for value in triangle_values {
+            let indices = self.f32_array(value, "a triangle")?;
+            let valid = |i: &f32| i.fract() == 0.0 && *i >= 0.0 && (*i as usize) < positions.len();
+            match indices[..] {
+                [a, b, c] if [a, b, c].iter().all(valid) => {
+                    triangles.push([a as u32, b as u32, c as u32])
+                }
+                _ => {
+                    return Err(self.error(
+                        value.span(),
+                        format!(
+                            "a triangle must be 3 vertex indices below {}",
+                            positions.len()
+                        ),
+                    ));
+                }
+            }
+        }
+        let vertex_count = positions.len();
+        let mut mesh = Mesh::new(positions, triangles);
+        if let Some(value) = table.get("normals") {
+            let normals = table.vec3_array("normals")?;
+            if normals.len() != vertex_count {
+                return Err(self.error(value.span(), "normals needs one entry per position".into()));
+            }
+            mesh = mesh.with_normals(normals);
+        }
+        if let Some(value) = table.get("uvs") {
+            let mut uvs = vec![];
+            for uv in self.array(value, "uvs")? {
+                match self.f32_array(uv, "a uv")?[..] {
+                    [u, v] => uvs.push([u, v]),
+                    _ => return Err(self.error(uv.span(), "a uv must be 2 numbers".into())),
+                }
+            }
+            if uvs.len() != vertex_count {
+                return Err(self.error(value.span(), "uvs needs one entry per position".into()));
+            }
+            mesh = mesh.with_uvs(uvs);
+        }
+        Ok(mesh)
+    }
+}
+
+impl<'p, 'i> Table<'p, 'i> {
+    fn get(&self, key: &str) -> Option<&'p Value<'i>> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.get_ref() == key)
+            .map(|(_, v)| v)
+    }
+
+    fn required(&self, key: &str) -> Result<&'p Value<'i>, SceneError> {
+        self.get(key)
+            .ok_or_else(|| self.error(&format!("missing required key '{key}'")))
+    }
+
+    fn span_of(&self, key: &str) -> Range<usize> {
+        self.get(key).map_or(self.span.clone(), |v| v.span())
+    }
+
+    fn error(&self, message: &str) -> SceneError {
+        self.parser.error(self.span.clone(), message.to_string())
+    }
+
+    fn error_at(&self, key: &str, message: &str) -> SceneError {
+        self.parser.error(self.span_of(key), message.to_string())
+    }
+
+    /// Rejects unknown keys, so typos don't silently fall back to defaults.
+    fn check_keys(&self, allowed: &[&str]) -> Result<(), SceneError> {
+        for (key, _) in self.entries.iter() {
+            if !allowed.contains(&key.get_ref().as_ref()) {
+                return Err(self.parser.error(
+                    key.span(),
+                    format!(
+                        "unknown key '{}', expected one of {}",
+                        key.get_ref(),
+                        allowed.join(", ")
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn f32(&self, key: &str) -> Result<f32, SceneError> {
+        self.parser.f32(self.required(key)?, key)
+    }
+
+    fn optional_f32(&self, key: &str) -> Result<Option<f32>, SceneError> {
+        self.get(key).map(|v| self.parser.f32(v, key)).transpose()
+    }
+
+    fn vec3(&self, key: &str) -> Result<Vec3, SceneError> {
+        self.parser.vec3(self.required(key)?, key)
+    }
+
+    fn optional_vec3(&self, key: &str) -> Result<Option<Vec3>, SceneError> {
+        self.get(key).map(|v| self.parser.vec3(v, key)).transpose()
+    }
+
+    fn vec3_array(&self, key: &str) -> Result<Vec<Vec3>, SceneError> {
+        let items = self.parser.array(self.required(key)?, key)?;
+        items.iter().map(|v| self.parser.vec3(v, key)).collect()
+    }
+
+    /// A vector whose components must not be negative.
+    fn color(&self, key: &str) -> Result<Color, SceneError> {
+        let color = self.vec3(key)?;
+        if color.x < 0.0 || color.y < 0.0 || color.z < 0.0 {
+            return Err(self.error_at(key, &format!("{key} must not have negative components")));
+        }
+        Ok(color)
+    }
+
+    fn texture(&self, key: &str) -> Result<Texture, SceneError> {
+        self.parser.texture(self.required(key)?, key)
+    }
+
+    fn optional_texture(&self, key: &str) -> Result<Option<Texture>, SceneError> {
+        self.get(key)
+            .map(|v| self.parser.texture(v, key))
+            .transpose()
+    }
+
+    fn optional_scalar_texture(&self, key: &str) -> Result<Option<Texture>, SceneError> {
+        self.get(key)
+            .map(|v| self.parser.scalar_texture(v, key))
+            .transpose()
+    }
+
+    fn string(&self, key: &str) -> Result<&'p str, SceneError> {
+        self.parser.string(self.required(key)?, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The message of the error `source` fails with, with its location.
+    fn error(source: &str) -> String {
+        match parse(source, Path::new("test.toml")) {
+            Ok(_) => panic!("parsed:\n{source}"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn sphere(material: &str) -> String {
+        format!(
+            "[materials.white]\n\
+             type = \"lambertian\"\n\
+             albedo = [0.8, 0.8, 0.8]\n\
+             \n\
+             [[shapes]]\n\
+             type = \"sphere\"\n\
+             center = [0.0, 0.0, -1.0]\n\
+             radius = 0.5\n\
+             material = \"{material}\"\n"
+        )
+    }
+
+    #[test]
+    fn parses_a_minimal_scene() {
+        let scene = parse(&sphere("white"), Path::new("test.toml")).unwrap();
+        assert_eq!(
+            scene.camera.vertical_fov,
+            CameraDescription::default().vertical_fov
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_materials() {
+        assert_eq!(
+            error(&sphere("black")),
+            "test.toml:9:12: unknown material 'black'"
+        );
+    }
+
+    #[test]
+    fn rejects_negative_index_of_refraction() {
+        let source = "[materials.glass]\n\
+                      type = \"dielectric\"\n\
+                      index_of_refraction = -1.5\n";
+        assert_eq!(
+            error(source),
+            "test.toml:3:23: index_of_refraction must be positive"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_vectors() {
+        assert_eq!(
+            error("[camera]\nlook_from = [0.0, 1.0]\n"),
+            "test.toml:2:13: look_from must be a vector of 3 numbers, found 2 numbers"
+        );
+        assert_eq!(
+            error("[camera]\nlook_at = [0.0, \"up\", 1.0]\n"),
+            "test.toml:2:17: look_at must be a finite number"
+        );
+        assert_eq!(
+            error("background = 1.0\n"),
+            "test.toml:1:14: background must be an array of numbers"
+        );
+    }
+
+    #[test]
+    fn rejects_up_along_the_view_direction() {
+        assert_eq!(
+            error(
+                "[camera]\n\
+                 look_from = [0.0, 0.0, 0.0]\n\
+                 look_at = [0.0, 0.0, -1.0]\n\
+                 up = [0.0, 0.0, 2.0]\n"
+            ),
+            "test.toml:4:6: up must not be zero or parallel to the view direction"
+        );
+    }
+
+    #[test]
+    fn rejects_meshes_without_triangles() {
+        let source = sphere("white").replace(
+            "type = \"sphere\"\ncenter = [0.0, 0.0, -1.0]\nradius = 0.5\n",
+            "type = \"mesh\"\npositions = [[0.0, 0.0, 0.0]]\ntriangles = []\n",
+        );
+        assert_eq!(
+            error(&source),
+            "test.toml:8:13: a mesh needs at least one triangle"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert_eq!(
+            error("[camera]\nlook_form = [0.0, 0.0, 0.0]\n"),
+            "test.toml:2:1: unknown key 'look_form', expected one of look_from, look_at, up, \
+             vertical_fov, aperture, focus_distance, shutter_open, shutter_close"
+        );
+    }
+
+    #[test]
+    fn reports_toml_syntax_errors() {
+        let message = error("[camera]\nlook_from = [0.0, 0.0\n");
+        assert!(message.starts_with("test.toml:2:"), "{message}");
+    }
+
+    #[test]
+    fn loads_every_shipped_scene() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let mut loaded = 0;
+        for entry in std::fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "toml") {
+                if let Err(e) = load(&path) {
+                    panic!("{e}");
+                }
+                loaded += 1;
+            }
+        }
+        assert!(loaded > 0);
+    }
+}
@@ -0,0 +1,515 @@
+//! Ways to estimate the light arriving along a camera ray. The path tracer
+//! renders the final image, the others trade correctness for speed or show
+//! what the renderer sees.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use crate::{
+    geo::Vec3,
+    random::MyRng,
+    render::{Color, Ray},
+    scene::{Background, Material, Scene},
+};
+
+/// Bounce limit of integrators that don't end paths by Russian roulette.
+pub const DEFAULT_MAX_BOUNCE_DEPTH: i32 = 50;
+
+pub const DEFAULT_ROULETTE_DEPTH: i32 = 3;
+
+pub trait Integrator: Sync {
+    /// Radiance arriving at the camera along `ray`, one sample of it for
+    /// stochastic integrators.
+    fn li(&self, ray: &Ray, scene: &Scene, rng: &mut impl MyRng) -> Color;
+}
+
+/// Unbiased path tracing, the reference all other integrators are compared
+/// to.
+#[derive(Debug, Clone, Copy)]
+pub struct PathTracer {
+    /// Hard limit on the bounces per path, `None` leaves ending paths to
+    /// Russian roulette alone. Paths cut off by the limit return black, so
+    /// any limit darkens the image a little.
+    pub max_depth: Option<i32>,
+    /// Bounces before Russian roulette starts ending paths.
+    pub roulette_depth: i32,
+    pub light_sampling: LightSampling,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LightSampling {
+    /// Random walk, light only counts when a path happens to hit it.
+    #[default]
+    None,
+    /// Also samples a light at every diffuse bounce. The following bounce
+    /// then skips the emission it hits, that light is already accounted for.
+    NextEvent,
+    /// Weighs light sampling against the material's own sampling by the
+    /// power heuristic. Works well for both small lights and glossy
+    /// reflections of large ones.
+    MultipleImportance,
+}
+
+impl Default for PathTracer {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            roulette_depth: DEFAULT_ROULETTE_DEPTH,
+            light_sampling: LightSampling::default(),
+        }
+    }
+}
+
+impl Integrator for PathTracer {
+    fn li(&self, ray: &Ray, scene: &Scene, rng: &mut impl MyRng) -> Color {
+        let mut ray = *ray;
+        let mut radiance = Color::zero();
+        let mut throughput = Color::of(1.0, 1.0, 1.0);
+        // Density with which the previous bounce picked `ray`, `None` for
+        // camera rays and specular bounces.
+        let mut scatter_pdf: Option<f32> = None;
+        // Where that bounce happened. Interfaces move `ray` on without
+        // changing it.
+        let mut vertex = ray.base;
+        let mut medium = scene.medium.as_ref();
+        let mut depth = 0;
+        loop {
+            if self.max_depth.is_some_and(|max_depth| depth >= max_depth) {
+                break;
+            }
+            let hit = scene.closest_hit_object(&ray, 0.001, f32::MAX);
+            if let Some(current) = medium
+                && let Some((_, hit_record)) = &hit
+            {
+                let sample = current.sample(&ray, hit_record.t, rng);
+                radiance += throughput * sample.emitted;
+                throughput = throughput * sample.weight;
+                if let Some(t) = sample.scattered_at {
+                    let p = ray.at(t);
+                    let dir_in = ray.dir.unit();
+                    let phase = current.phase();
+                    if self.light_sampling != LightSampling::None
+                        && let Some(light) = scene.sample_light(&p, ray.time, rng)
+                    {
+                        let transmittance = scene.transmittance(
+                            &p,
+                            &light.dir,
+                            light.distance,
+                            ray.time,
+                            medium,
+                            rng,
+                        );
+                        let phase_value = phase.value(&dir_in, &light.dir);
+                        let weight = match self.light_sampling {
+                            LightSampling::MultipleImportance => {
+                                power_heuristic(light.pdf, phase_value)
+                            }
+                            LightSampling::None | LightSampling::NextEvent => 1.0,
+                        };
+                        radiance += (weight * phase_value / light.pdf)
+                            * throughput
+                            * transmittance
+                            * light.emitted;
+                    }
+                    // The phase function is sampled exactly, the throughput
+                    // stays.
+                    let dir_out = phase.sample(&dir_in, rng);
+                    scatter_pdf = Some(phase.value(&dir_in, &dir_out));
+                    ray = ray.spawn(p, dir_out);
+                    vertex = p;
+                    depth += 1;
+                    if depth >= self.roulette_depth && !survives_roulette(&mut throughput, rng) {
+                        break;
+                    }
+                    continue;
+                }
+            }
+            let Some((object, hit_record)) = hit else {
+                let background_pdf = scene.background_pdf(&ray.dir);
+                let weight = match (self.light_sampling, scatter_pdf) {
+                    (LightSampling::None, _) | (_, None) => 1.0,
+                    (LightSampling::NextEvent, Some(_)) if background_pdf > 0.0 => 0.0,
+                    (LightSampling::NextEvent, Some(_)) => 1.0,
+                    (LightSampling::MultipleImportance, Some(scatter_pdf)) => {
+                        power_heuristic(scatter_pdf, background_pdf)
+                    }
+                };
+                radiance += weight * throughput * scene.background.color(&ray.dir);
+                break;
+            };
+            if matches!(hit_record.material, Material::Interface) {
+                medium = scene.medium_behind(object, &hit_record, &ray.dir, medium);
+                ray = ray.spawn(hit_record.p, ray.dir);
+                continue;
+            }
+
+            let emitted = hit_record.material.emitted(&hit_record.face);
+            if !emitted.near_zero() {
+                let weight = match (self.light_sampling, scatter_pdf) {
+                    (LightSampling::None, _) | (_, None) => 1.0,
+                    // Lights that are never sampled are only found here.
+                    (LightSampling::NextEvent, Some(_)) if scene.is_light(object) => 0.0,
+                    (LightSampling::NextEvent, Some(_)) => 1.0,
+                    (LightSampling::MultipleImportance, Some(scatter_pdf)) => {
+                        let light_pdf = scene.light_pdf(object, &vertex, &hit_record);
+                        power_heuristic(scatter_pdf, light_pdf)
+                    }
+                };
+                radiance += weight * throughput * emitted;
+            }
+
+            let Some(scatter_result) = hit_record.material.scatter(&ray, &hit_record, rng) else {
+                break;
+            };
+            if self.light_sampling != LightSampling::None
+                && scatter_result.pdf.is_some()
+                && let Some(light) = scene.sample_light(&hit_record.p, ray.time, rng)
+            {
+                let material = hit_record.material;
+                let light_medium = scene.medium_behind(object, &hit_record, &light.dir, medium);
+                let transmittance = scene.transmittance(
+                    &hit_record.p,
+                    &light.dir,
+                    light.distance,
+                    ray.time,
+                    light_medium,
+                    rng,
+                );
+                let weight = match self.light_sampling {
+                    LightSampling::MultipleImportance => {
+                        power_heuristic(light.pdf, material.pdf(&ray, &hit_record, &light.dir))
+                    }
+                    LightSampling::None | LightSampling::NextEvent => 1.0,
+                };
+                let bsdf_cos = material.evaluate(&ray, &hit_record, &light.dir);
+                radiance +=
+                    (weight / light.pdf) * throughput * bsdf_cos * transmittance * light.emitted;
+            }
+
+            throughput = throughput * scatter_result.attenuation;
+            scatter_pdf = scatter_result.pdf;
+            ray = scatter_result.ray_out;
+            vertex = hit_record.p;
+            medium = scene.medium_behind(object, &hit_record, &ray.dir, medium);
+            depth += 1;
+            if depth >= self.roulette_depth && !survives_roulette(&mut throughput, rng) {
+                break;
+            }
+        }
+        radiance
+    }
+}
+
+/// Ends a path with a probability that grows as its throughput drops. The
+/// throughput of survivors is scaled up to keep the estimate unbiased.
+fn survives_roulette(throughput: &mut Color, rng: &mut impl MyRng) -> bool {
+    // Never certain, so paths that keep all their energy still end.
+    let survival = throughput.x.max(throughput.y).max(throughput.z).min(0.95);
+    if rng.random_f32() >= survival {
+        return false;
+    }
+    *throughput /= survival;
+    true
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    // Squares of the ratio, the squared densities overflow for far away
+    // points of infinite planes.
+    if pdf >= other_pdf {
+        if pdf == 0.0 {
+            return 0.0;
+        }
+        1.0 / (1.0 + (other_pdf / pdf).powi(2))
+    } else {
+        let ratio = (pdf / other_pdf).powi(2);
+        ratio / (1.0 + ratio)
+    }
+}
+
+/// Classic Whitted style ray tracing. Diffuse surfaces see the lights
+/// through shadow rays and the background in the direction of their normal
+/// as ambient light, metals are perfect mirrors and glass follows `scatter`.
+/// Environment maps are lights rather than ambient light. No indirect
+/// diffuse light, so it is fast but biased. Media only dim shadow rays.
+#[derive(Debug, Clone, Copy)]
+pub struct Whitted {
+    pub max_depth: i32,
+}
+
+impl Integrator for Whitted {
+    fn li(&self, ray: &Ray, scene: &Scene, rng: &mut impl MyRng) -> Color {
+        // Every surface continues the ray in at most one direction, so the
+        // recursion unrolls into a loop.
+        let mut ray = *ray;
+        let mut radiance = Color::zero();
+        let mut throughput = Color::of(1.0, 1.0, 1.0);
+        for _ in 0..self.max_depth {
+            let Some(hit_record) = scene.closest_hit(&ray, 0.001, f32::MAX) else {
+                radiance += throughput * scene.background.color(&ray.dir);
+                break;
+            };
+            radiance += throughput * hit_record.material.emitted(&hit_record.face);
+            match hit_record.material {
+                Material::Lambertian { albedo } => {
+                    if let Some(light) = scene.sample_light(&hit_record.p, ray.time, rng) {
+                        let p = &hit_record.p;
+                        let transmittance =
+                            scene.transmittance(p, &light.dir, light.distance, ray.time, None, rng);
+                        let bsdf_cos = hit_record.material.evaluate(&ray, &hit_record, &light.dir);
+                        radiance += (1.0 / light.pdf)
+                            * throughput
+                            * bsdf_cos
+                            * transmittance
+                            * light.emitted;
+                    }
+                    // Environment maps are already sampled as lights.
+                    if !matches!(scene.background, Background::Map(_)) {
+                        let albedo = hit_record.color_of(albedo);
+                        let ambient = albedo * scene.background.color(&hit_record.normal);
+                        radiance += throughput * ambient;
+                    }
+                    break;
+                }
+                Material::Metal { albedo, .. } => {
+                    throughput = throughput * hit_record.color_of(albedo);
+                    ray = ray.spawn(hit_record.p, ray.dir.unit().reflect(&hit_record.normal));
+                }
+                Material::Conductor { ior, .. } => {
+                    let cos_theta = -ray.dir.unit().dot(&hit_record.normal);
+                    throughput = throughput * ior.reflectance(cos_theta);
+                    ray = ray.spawn(hit_record.p, ray.dir.unit().reflect(&hit_record.normal));
+                }
+                Material::Dieletric { .. }
+                | Material::Principled(_)
+                | Material::DiffuseLight { .. }
+                | Material::Interface => {
+                    let Some(scatter_result) = hit_record.material.scatter(&ray, &hit_record, rng)
+                    else {
+                        break;
+                    };
+                    throughput = throughput * scatter_result.attenuation;
+                    ray = scatter_result.ray_out;
+                }
+            }
+        }
+        radiance
+    }
+}
+
+/// Fraction of the hemisphere above the first hit that is open up to
+/// `distance`, cosine weighted. White where nothing is hit.
+#[derive(Debug, Clone, Copy)]
+pub struct AmbientOcclusion {
+    pub distance: f32,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn li(&self, ray: &Ray, scene: &Scene, rng: &mut impl MyRng) -> Color {
+        let Some(hit_record) = scene.closest_hit(ray, 0.001, f32::MAX) else {
+            return Color::of(1.0, 1.0, 1.0);
+        };
+        let mut dir = hit_record.normal + Vec3::random_on_unit_sphere(rng);
+        if dir.near_zero() {
+            dir = hit_record.normal;
+        }
+        let occlusion_ray = Ray::new(hit_record.p, dir.unit()).with_time(ray.time);
+        let distance = self.distance.min(f32::MAX);
+        if scene.closest_hit(&occlusion_ray, 0.001, distance).is_none() {
+            Color::of(1.0, 1.0, 1.0)
+        } else {
+            Color::zero()
+        }
+    }
+}
+
+/// Shows a property of the first hit instead of light. Misses are black.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugView {
+    /// Shading normal facing the camera, mapped from [-1, 1] to [0, 1].
+    Normals,
+    /// Distance along the camera ray, unscaled. Best saved as .exr or .pfm.
+    Depth,
+    /// Texture coordinates in red and green.
+    Uvs,
+    /// A colour per object's material. Instances show the materials of the
+    /// objects they copy.
+    MaterialId,
+    /// The material's base colour, white for glass.
+    Albedo,
+}
+
+impl Integrator for DebugView {
+    fn li(&self, ray: &Ray, scene: &Scene, _rng: &mut impl MyRng) -> Color {
+        let Some((object, hit_record)) = scene.closest_hit_object(ray, 0.001, f32::MAX) else {
+            return Color::zero();
+        };
+        match self {
+            DebugView::Normals => 0.5 * (hit_record.normal + Color::of(1.0, 1.0, 1.0)),
+            DebugView::Depth => {
+                let depth = hit_record.t * ray.dir.length();
+                Color::of(depth, depth, depth)
+            }
+            DebugView::Uvs => Color::of(hit_record.uv[0], hit_record.uv[1], 0.0),
+            DebugView::MaterialId => {
+                let mut hasher = DefaultHasher::new();
+                object.material_id.hash(&mut hasher);
+                let [r, g, b, ..] = hasher.finish().to_le_bytes();
+                Color::of(r as f32, g as f32, b as f32) / 255.0
+            }
+            DebugView::Albedo => match hit_record.material {
+                Material::Lambertian { albedo } | Material::Metal { albedo, .. } => {
+                    hit_record.color_of(albedo)
+                }
+                Material::Conductor { ior, .. } => ior.reflectance(1.0),
+                Material::Dieletric { .. } | Material::Interface => Color::of(1.0, 1.0, 1.0),
+                Material::Principled(principled) => hit_record.color_of(&principled.base_color),
+                Material::DiffuseLight { emit } => *emit,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{self, Camera, Image, RenderSettings};
+
+    /// Unit Cornell box, open at the front, with a square light in the
+    /// ceiling and two diffuse spheres.
+    fn cornell_box() -> Scene {
+        let mut scene = Scene::new();
+        scene.set_background(Background::Solid(Color::zero()));
+        let diffuse = |r, g, b| Material::Lambertian {
+            albedo: Color::of(r, g, b).into(),
+        };
+        let white = diffuse(0.73, 0.73, 0.73);
+        let (x, y, z) = (
+            Vec3::of(1.0, 0.0, 0.0),
+            Vec3::of(0.0, 1.0, 0.0),
+            Vec3::of(0.0, 0.0, 1.0),
+        );
+        scene.add_quad(Vec3::zero(), x, z, white.clone());
+        scene.add_quad(y, x, z, white.clone());
+        scene.add_quad(z, x, y, white.clone());
+        scene.add_quad(Vec3::zero(), y, z, diffuse(0.65, 0.05, 0.05));
+        scene.add_quad(x, y, z, diffuse(0.12, 0.45, 0.15));
+        // Faces down.
+        scene.add_quad(
+            Vec3::of(0.25, 0.999, 0.25),
+            0.5 * x,
+            0.5 * z,
+            Material::DiffuseLight {
+                emit: Color::of(5.0, 5.0, 5.0),
+            },
+        );
+        scene.add_sphere(Vec3::of(0.3, 0.15, 0.35), 0.15, white.clone());
+        scene.add_sphere(Vec3::of(0.65, 0.2, 0.65), 0.2, white);
+        scene
+    }
+
+    /// Mean over a small image looking down +z from (0.5, 0.5, -1.4).
+    fn mean_radiance(scene: &Scene, integrator: &impl Integrator, spp: i32) -> Color {
+        let camera = Camera::new(
+            Vec3::of(0.5, 0.5, -1.4),
+            Vec3::of(0.5, 0.5, 0.0),
+            Vec3::of(0.0, 1.0, 0.0),
+            40.0,
+            1.0,
+        );
+        let (width, height) = (16, 16);
+        let mut image = Image::empty(width, height, spp);
+        let settings = RenderSettings {
+            n_threads: 4,
+            seed: Some(7),
+        };
+        render::render(&camera, scene, integrator, &mut image, &settings);
+        let pixels = image.averaged();
+        pixels.iter().fold(Color::zero(), |sum, c| sum + *c) / pixels.len() as f32
+    }
+
+    fn assert_close(a: Color, b: Color, tolerance: f32) {
+        for (a, b) in [(a.x, b.x), (a.y, b.y), (a.z, b.z)] {
+            assert!(
+                (a - b).abs() <= tolerance * b.abs(),
+                "{a} and {b} differ by more than {tolerance} relative"
+            );
+        }
+    }
+
+    #[test]
+    fn next_event_estimation_matches_path_tracing() {
+        let scene = cornell_box();
+        let path = mean_radiance(&scene, &PathTracer::default(), 1024);
+        let nee = mean_radiance(
+            &scene,
+            &PathTracer {
+                light_sampling: LightSampling::NextEvent,
+                ..PathTracer::default()
+            },
+            512,
+        );
+        assert_close(nee, path, 0.03);
+    }
+
+    /// Path tracers with roulette from the first bounce and without it.
+    fn with_and_without_roulette() -> [PathTracer; 2] {
+        [
+            PathTracer {
+                roulette_depth: 0,
+                ..PathTracer::default()
+            },
+            PathTracer {
+                roulette_depth: i32::MAX,
+                ..PathTracer::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn roulette_keeps_white_furnace_white() {
+        // A white box around the camera, open at the top, lit by a uniform
+        // white background. Every path bounces until it leaves through the
+        // top, so the exact radiance is 1 everywhere. The roulette survival
+        // is capped at 0.95, so an opening much smaller than a sixth of the
+        // box would make the variance infinite.
+        let mut scene = Scene::new();
+        scene.set_background(Background::Solid(Color::of(1.0, 1.0, 1.0)));
+        let white = Material::Lambertian {
+            albedo: Color::of(1.0, 1.0, 1.0).into(),
+        };
+        let (x, y, z) = (
+            Vec3::of(5.0, 0.0, 0.0),
+            Vec3::of(0.0, 5.0, 0.0),
+            Vec3::of(0.0, 0.0, 5.0),
+        );
+        let min = Vec3::of(-2.0, -2.0, -3.0);
+        scene.add_quad(min, x, z, white.clone());
+        scene.add_quad(min, x, y, white.clone());
+        scene.add_quad(min + z, x, y, white.clone());
+        scene.add_quad(min, y, z, white.clone());
+        scene.add_quad(min + x, y, z, white);
+        for integrator in with_and_without_roulette() {
+            let mean = mean_radiance(&scene, &integrator, 256);
+            assert_close(mean, Color::of(1.0, 1.0, 1.0), 0.01);
+        }
+    }
+
+    #[test]
+    fn roulette_keeps_coloured_furnace_unbiased() {
+        // A sphere filling the view under a uniform background. Light leaves
+        // a convex surface after one bounce, so the radiance is its albedo.
+        let albedo = Color::of(0.2, 0.5, 0.8);
+        let mut scene = Scene::new();
+        scene.set_background(Background::Solid(Color::of(1.0, 1.0, 1.0)));
+        scene.add_sphere(
+            Vec3::of(0.5, 0.5, 0.5),
+            1.5,
+            Material::Lambertian {
+                albedo: albedo.into(),
+            },
+        );
+        for integrator in with_and_without_roulette() {
+            let mean = mean_radiance(&scene, &integrator, 256);
+            assert_close(mean, albedo, 0.01);
+        }
+    }
+}
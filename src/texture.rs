@@ -0,0 +1,275 @@
+use std::sync::{Arc, OnceLock};
+
+use crate::{
+    geo::Vec3,
+    image_io::FloatImage,
+    random::{MyRng, MySmallRng},
+    render::Color,
+};
+
+/// A colour that varies over a surface, looked up by texture coordinates or
+/// by the position in space.
+#[derive(Debug, Clone)]
+pub enum Texture {
+    Solid(Color),
+    /// Cubes with edges of length `size` alternating between `even` and
+    /// `odd`, carved out of space like a solid block.
+    Checker {
+        even: Box<Texture>,
+        odd: Box<Texture>,
+        size: f32,
+    },
+    /// Squares with edges of length `size` in texture coordinates.
+    UvChecker {
+        even: Box<Texture>,
+        odd: Box<Texture>,
+        size: f32,
+    },
+    /// `color` modulated by Perlin noise, `scale` is the frequency.
+    Noise {
+        kind: NoiseKind,
+        scale: f32,
+        color: Color,
+    },
+    /// Filtered image, `v` runs from the bottom row to the top.
+    Image {
+        image: Arc<MipMap>,
+        wrap: WrapMode,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseKind {
+    /// Smooth noise.
+    Perlin,
+    /// Several octaves of noise, for clouds and smoke.
+    Turbulence,
+    /// Veins along the z axis, distorted by turbulence.
+    Marble,
+}
+
+/// What image textures show outside of [0, 1] texture coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapMode {
+    Repeat,
+    /// Repeats the edge texels.
+    Clamp,
+    /// Repeats the image mirrored every other time.
+    Mirror,
+}
+
+impl From<Color> for Texture {
+    fn from(color: Color) -> Self {
+        Texture::Solid(color)
+    }
+}
+
+impl Texture {
+    /// `uv_width` is the width of the ray's footprint in texture coordinates,
+    /// image textures average over it.
+    pub fn value(&self, uv: [f32; 2], uv_width: f32, p: &Vec3) -> Color {
+        match self {
+            Texture::Solid(color) => *color,
+            Texture::Checker { even, odd, size } => {
+                let cell = |x: f32| (x / size).floor() as i64;
+                if (cell(p.x) + cell(p.y) + cell(p.z)).rem_euclid(2) == 0 {
+                    even.value(uv, uv_width, p)
+                } else {
+                    odd.value(uv, uv_width, p)
+                }
+            }
+            Texture::UvChecker { even, odd, size } => {
+                let cell = |x: f32| (x / size).floor() as i64;
+                if (cell(uv[0]) + cell(uv[1])).rem_euclid(2) == 0 {
+                    even.value(uv, uv_width, p)
+                } else {
+                    odd.value(uv, uv_width, p)
+                }
+            }
+            Texture::Noise { kind, scale, color } => {
+                let perlin = Perlin::shared();
+                let p = *scale * p;
+                let intensity = match kind {
+                    NoiseKind::Perlin => 0.5 * (1.0 + perlin.noise(&p)),
+                    NoiseKind::Turbulence => perlin.turbulence(&p, 7),
+                    NoiseKind::Marble => {
+                        0.5 * (1.0 + (p.z + 10.0 * perlin.turbulence(&p, 7)).sin())
+                    }
+                };
+                intensity * color
+            }
+            Texture::Image { image, wrap } => image.lookup(uv, uv_width, *wrap),
+        }
+    }
+}
+
+/// An image and successively halved copies of it, down to a single texel.
+/// Lookups blend the two copies whose texels best match the ray's footprint,
+/// so distant surfaces show the average colour instead of aliasing.
+#[derive(Debug)]
+pub struct MipMap {
+    levels: Vec<FloatImage>,
+}
+
+impl MipMap {
+    pub fn new(image: FloatImage) -> Self {
+        let mut levels = vec![image];
+        while let Some(level) = levels.last()
+            && (level.width > 1 || level.height > 1)
+        {
+            levels.push(downsample(level));
+        }
+        Self { levels }
+    }
+
+    /// The full resolution image.
+    pub fn image(&self) -> &FloatImage {
+        &self.levels[0]
+    }
+
+    fn lookup(&self, uv: [f32; 2], uv_width: f32, wrap: WrapMode) -> Color {
+        let image = self.image();
+        let texels = uv_width * image.width.max(image.height) as f32;
+        // Footprints narrower than a texel use the full resolution.
+        let level = texels.max(1.0).log2().min((self.levels.len() - 1) as f32);
+        let lower = level.floor() as usize;
+        let fraction = level - lower as f32;
+        let color = bilinear(&self.levels[lower], wrap, uv);
+        if fraction == 0.0 {
+            return color;
+        }
+        (1.0 - fraction) * color + fraction * bilinear(&self.levels[lower + 1], wrap, uv)
+    }
+}
+
+/// Halves both sides, rounding up, by averaging blocks of 2x2 texels. Odd
+/// sides repeat their last row or column to fill the blocks at the edge.
+fn downsample(image: &FloatImage) -> FloatImage {
+    let width = image.width.div_ceil(2);
+    let height = image.height.div_ceil(2);
+    let texel = |x: usize, y: usize| {
+        image.pixels[y.min(image.height - 1) * image.width + x.min(image.width - 1)]
+    };
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let sum = texel(2 * x, 2 * y)
+                + texel(2 * x + 1, 2 * y)
+                + texel(2 * x, 2 * y + 1)
+                + texel(2 * x + 1, 2 * y + 1);
+            pixels.push(0.25 * sum);
+        }
+    }
+    FloatImage {
+        width,
+        height,
+        pixels,
+    }
+}
+
+fn bilinear(image: &FloatImage, wrap: WrapMode, uv: [f32; 2]) -> Color {
+    // Texel centres sit at half integer coordinates.
+    let x = uv[0] * image.width as f32 - 0.5;
+    let y = (1.0 - uv[1]) * image.height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let texel = |x: f32, y: f32| {
+        let column = wrap_index(x as i64, image.width, wrap);
+        let row = wrap_index(y as i64, image.height, wrap);
+        image.pixels[row * image.width + column]
+    };
+    (1.0 - fy) * ((1.0 - fx) * texel(x0, y0) + fx * texel(x0 + 1.0, y0))
+        + fy * ((1.0 - fx) * texel(x0, y0 + 1.0) + fx * texel(x0 + 1.0, y0 + 1.0))
+}
+
+fn wrap_index(i: i64, len: usize, wrap: WrapMode) -> usize {
+    let len = len as i64;
+    let wrapped = match wrap {
+        WrapMode::Repeat => i.rem_euclid(len),
+        WrapMode::Clamp => i.clamp(0, len - 1),
+        WrapMode::Mirror => {
+            let i = i.rem_euclid(2 * len);
+            if i < len { i } else { 2 * len - 1 - i }
+        }
+    };
+    wrapped as usize
+}
+
+const POINT_COUNT: usize = 256;
+
+/// Gradient noise on an integer lattice, as in "Ray Tracing: The Next Week".
+struct Perlin {
+    gradients: [Vec3; POINT_COUNT],
+    permutations: [[usize; POINT_COUNT]; 3],
+}
+
+impl Perlin {
+    /// All noise textures share one lattice, built from a fixed seed so
+    /// renders are reproducible.
+    fn shared() -> &'static Perlin {
+        static PERLIN: OnceLock<Perlin> = OnceLock::new();
+        PERLIN.get_or_init(|| Perlin::new(&mut MySmallRng::from_seed(0x5eed)))
+    }
+
+    fn new(rng: &mut impl MyRng) -> Self {
+        let gradients = std::array::from_fn(|_| Vec3::random_in_range(-1.0, 1.0, rng).unit());
+        let permutations = std::array::from_fn(|_| {
+            let mut permutation: [usize; POINT_COUNT] = std::array::from_fn(|i| i);
+            for i in (1..POINT_COUNT).rev() {
+                let target = ((rng.random_f32() * (i + 1) as f32) as usize).min(i);
+                permutation.swap(i, target);
+            }
+            permutation
+        });
+        Self {
+            gradients,
+            permutations,
+        }
+    }
+
+    /// Smooth noise in [-1, 1].
+    fn noise(&self, p: &Vec3) -> f32 {
+        let floor = [p.x.floor(), p.y.floor(), p.z.floor()];
+        let fraction = [p.x - floor[0], p.y - floor[1], p.z - floor[2]];
+        let cell = floor.map(|f| f as i64);
+
+        // Hermite smoothing hides the lattice.
+        let [u, v, w] = fraction.map(|t| t * t * (3.0 - 2.0 * t));
+        let mut sum = 0.0;
+        for i in 0..2 {
+            for j in 0..2 {
+                for k in 0..2 {
+                    let index = |axis: usize, offset: i64| {
+                        self.permutations[axis][((cell[axis] + offset) & 255) as usize]
+                    };
+                    let gradient = self.gradients[index(0, i) ^ index(1, j) ^ index(2, k)];
+                    let weight = Vec3::of(
+                        fraction[0] - i as f32,
+                        fraction[1] - j as f32,
+                        fraction[2] - k as f32,
+                    );
+                    let (fi, fj, fk) = (i as f32, j as f32, k as f32);
+                    sum += (fi * u + (1.0 - fi) * (1.0 - u))
+                        * (fj * v + (1.0 - fj) * (1.0 - v))
+                        * (fk * w + (1.0 - fk) * (1.0 - w))
+                        * gradient.dot(&weight);
+                }
+            }
+        }
+        sum
+    }
+
+    /// Absolute value of `depth` octaves of noise summed up, each at double
+    /// the frequency and half the weight of the previous one.
+    fn turbulence(&self, p: &Vec3, depth: usize) -> f32 {
+        let mut sum = 0.0;
+        let mut p = *p;
+        let mut weight = 1.0;
+        for _ in 0..depth {
+            sum += weight * self.noise(&p);
+            weight *= 0.5;
+            p = 2.0 * p;
+        }
+        sum.abs()
+    }
+}